@@ -1,23 +1,159 @@
 use http::{HeaderValue, StatusCode};
+use lru::LruCache;
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use nanoid::nanoid;
-use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use rmpv::Value;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use socketioxide::{
     extract::{AckSender, Data, SocketRef, State},
+    socket::Sid,
     SocketIo,
 };
 use std::env;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::hash::Hash;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
 };
-use tower_http::cors::CorsLayer;
-use tracing::info;
-use tracing_subscriber::FmtSubscriber;
+use tokio::task::AbortHandle;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{info, info_span, warn, Instrument, Span};
+use tracing_subscriber::EnvFilter;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+/// Public Jikan API, used unless `JIKAN_BASE_URL` points elsewhere.
+const JIKAN_BASE_URL: &str = "https://api.jikan.moe/v4";
+
+/// How long to wait for a connection to Jikan to be established.
+const JIKAN_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times a rate-limited Jikan request is retried before giving up.
+const JIKAN_MAX_RETRIES: u32 = 3;
+
+/// How many Jikan calls in a row must fail before `/readyz` reports the
+/// instance as not ready.
+const JIKAN_UNREADY_AFTER: u32 = 5;
+
+/// Pause between fetching consecutive pages of an anime listing, keeping
+/// well under Jikan's three requests a second.
+const JIKAN_PAGE_DELAY: Duration = Duration::from_millis(500);
+
+/// How often Jikan cache hit rates are logged.
+const JIKAN_CACHE_REPORT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Characters game codes are drawn from: uppercase letters and digits, minus
+/// the easily confused `0`, `O`, `1` and `I`.
+const GAME_CODE_ALPHABET: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// How long an anime id Jikan had no entry for is rejected without asking
+/// again.
+const UNKNOWN_ANIME_TTL: Duration = Duration::from_secs(300);
+
+/// How often abandoned lobbies are looked for.
+const LOBBY_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest game or player id accepted, in bytes.
+const MAX_ID_LEN: usize = 64;
+
+/// Longest display name kept, in characters; longer ones are cut short.
+const MAX_NAME_LEN: usize = 24;
+
+/// Longest chat message accepted, in characters.
+const MAX_CHAT_LEN: usize = 500;
+
+/// Most entries kept in a game's turn history; later turns go unrecorded.
+const MAX_HISTORY: usize = 1000;
+
+/// Players per team when a team game doesn't set `max_players`.
+const TEAM_SIZE: usize = 2;
+
+/// Seconds each contender gets for their sudden-death turn.
+const SUDDEN_DEATH_SECS: u64 = 20;
+
+/// Most anime a single game may block on top of the server's list.
+const MAX_BLOCKED_ANIME: usize = 500;
+
+/// How long in-flight requests get to finish once shutdown begins.
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(5);
+
+/// Most `thinking` events relayed per socket per second.
+const THINKING_RATE: u32 = 1;
+
+/// Most `get_valid_moves` requests answered per socket per second.
+const VALID_MOVES_RATE: u32 = 1;
+
+/// Voice actors on the current anime whose roles are looked through for
+/// `get_valid_moves`; each one not yet cached costs a Jikan call.
+const VALID_MOVES_ACTORS: usize = 5;
+
+/// Most anime a `get_valid_moves` reply lists.
+const MAX_VALID_MOVES: usize = 10;
+
+/// How long the other players have to agree to undo the last move.
+const UNDO_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long the other players have to accept a rematch request.
+const REMATCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lock access that survives poisoning. A handler panicking mid-update may
+/// leave one game in an odd state, but that beats every later lobby call
+/// panicking too.
+trait Recover<'a> {
+    type Read;
+    type Write;
+
+    fn read_or_recover(&'a self) -> Self::Read;
+    fn write_or_recover(&'a self) -> Self::Write;
+}
+
+impl<'a, T: 'a> Recover<'a> for RwLock<T> {
+    type Read = RwLockReadGuard<'a, T>;
+    type Write = RwLockWriteGuard<'a, T>;
+
+    fn read_or_recover(&'a self) -> Self::Read {
+        self.read().unwrap_or_else(|e| {
+            warn!("recovering poisoned lock");
+            e.into_inner()
+        })
+    }
+
+    fn write_or_recover(&'a self) -> Self::Write {
+        self.write().unwrap_or_else(|e| {
+            warn!("recovering poisoned lock");
+            e.into_inner()
+        })
+    }
+}
+
+impl<'a, T: 'a> Recover<'a> for Mutex<T> {
+    type Read = MutexGuard<'a, T>;
+    type Write = MutexGuard<'a, T>;
+
+    fn read_or_recover(&'a self) -> Self::Read {
+        self.write_or_recover()
+    }
+
+    fn write_or_recover(&'a self) -> Self::Write {
+        self.lock().unwrap_or_else(|e| {
+            warn!("recovering poisoned lock");
+            e.into_inner()
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 struct PlayerId(String);
 
@@ -25,238 +161,7332 @@ struct PlayerId(String);
 #[serde(transparent)]
 struct GameId(String);
 
-#[derive(Deserialize, Serialize, Debug)]
-struct EventData {
-    game_id: String,
-    player_id: String,
-}
+/// Reconnect token of a spectating socket; carried over to whichever
+/// socket watches under it next.
+#[derive(Debug, Clone)]
+struct SpectatorToken(String);
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Anime {
-    mal_id: u32,
+/// Whether a socket attached to a game is playing or only watching it.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Player,
+    Spectator,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct MALResponse {
-    data: Vec<Anime>,
+/// Anime types Jikan accepts for the seed pool filter.
+const ANIME_TYPES: &[&str] = &["tv", "movie", "ova", "special", "ona", "music"];
+
+/// Host-chosen filters for the pool the seed anime is drawn from.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+struct GameConfig {
+    anime_type: Option<String>,
+    genre_id: Option<u32>,
+    min_year: Option<u16>,
+    /// Forbid linking through a voice actor who already made a link earlier
+    /// in the chain.
+    #[serde(default)]
+    no_reused_actors: bool,
+    /// End the game once the chain holds this many anime, seed included.
+    #[serde(default)]
+    target_chain_length: Option<u32>,
+    /// Seconds per turn, overriding the server's `TURN_SECS`. Zero turns the
+    /// timer off, so turns never run out.
+    #[serde(default)]
+    turn_secs: Option<u64>,
+    #[serde(default)]
+    max_players: Option<usize>,
+    /// Times a turn may be extended, overriding the server's `MAX_EXTENDS`.
+    #[serde(default)]
+    max_extends: Option<u32>,
+    /// Anime this game excludes on top of the server's `BLOCKED_ANIME`.
+    #[serde(default)]
+    blocked_anime: Vec<u32>,
+    /// Keep turn deadlines from spectators; players always get them.
+    #[serde(default)]
+    hide_spectator_timer: bool,
+    /// Let players ask for `get_valid_moves` hints.
+    #[serde(default)]
+    hints: bool,
+    /// Seconds a whole game may last, overriding the server's
+    /// `MAX_GAME_SECS`. Zero for no limit.
+    #[serde(default)]
+    max_game_secs: Option<u64>,
+    /// Turns a player may run out of time on before losing, overriding the
+    /// server's `LIVES`.
+    #[serde(default)]
+    lives: Option<u32>,
+    /// Split the players into two teams that take turns and score together.
+    /// Leaving `max_players` unset makes it a 2v2.
+    #[serde(default)]
+    teams: bool,
+    /// Start from this anime instead of drawing one from the pool, as a
+    /// challenge code does.
+    #[serde(default)]
+    seed_anime: Option<u32>,
+    /// Anime types players may submit, from `ANIME_TYPES`. Empty allows any;
+    /// unlike `anime_type` this applies to every move, not just the seed.
+    #[serde(default)]
+    allowed_types: Vec<String>,
+    /// Settle a game that ends tied on score with sudden death rather than
+    /// calling it.
+    #[serde(default)]
+    sudden_death: bool,
 }
 
-#[derive(Clone, Default, Debug)]
-struct Lobby(Arc<RwLock<HashMap<String, (String, Option<String>)>>>);
+impl GameConfig {
+    fn validate(&self) -> Result<(), &'static str> {
+        if let Some(anime_type) = &self.anime_type {
+            if !ANIME_TYPES.contains(&anime_type.as_str()) {
+                return Err("unknown anime type");
+            }
+        }
 
-enum LobbyResult {
-    New,
-    Paired(String),
-    Full,
-}
+        if self.genre_id.is_some_and(|id| !(1..=100).contains(&id)) {
+            return Err("unknown genre");
+        }
 
-impl Lobby {
-    fn insert(&self, game_id: String, player_id: String) -> LobbyResult {
-        let mut lock = self.0.write().unwrap();
+        if self
+            .min_year
+            .is_some_and(|year| !(1917..=2100).contains(&year))
+        {
+            return Err("invalid year");
+        }
 
-        if let Some((p1, p2)) = lock.get_mut(&game_id) {
-            if p2.is_some() {
-                return LobbyResult::Full;
-            }
+        if self.target_chain_length.is_some_and(|len| len < 2) {
+            return Err("target chain length must be at least 2");
+        }
+
+        if self
+            .turn_secs
+            .is_some_and(|secs| secs != 0 && !(5..=3600).contains(&secs))
+        {
+            return Err("turn length must be 0 or between 5 and 3600 seconds");
+        }
+
+        if self.max_players.is_some_and(|n| !(2..=6).contains(&n)) {
+            return Err("max players must be between 2 and 6");
+        }
+
+        if self.max_extends.is_some_and(|n| n > 10) {
+            return Err("max extends must be at most 10");
+        }
+
+        if self
+            .max_game_secs
+            .is_some_and(|secs| secs != 0 && !(60..=86400).contains(&secs))
+        {
+            return Err("game length must be 0 or between 60 and 86400 seconds");
+        }
+
+        if self.lives.is_some_and(|n| !(1..=10).contains(&n)) {
+            return Err("lives must be between 1 and 10");
+        }
+
+        if self.blocked_anime.len() > MAX_BLOCKED_ANIME {
+            return Err("too many blocked anime");
+        }
 
-            *p2 = Some(player_id);
+        if self.teams && self.max_players.is_some_and(|n| n % 2 != 0) {
+            return Err("team games need an even number of players");
+        }
 
-            return LobbyResult::Paired(p1.to_string());
+        if self
+            .allowed_types
+            .iter()
+            .any(|t| !ANIME_TYPES.contains(&t.as_str()))
+        {
+            return Err("unknown allowed anime type");
         }
 
-        lock.insert(game_id, (player_id, None));
+        if let Some(seed) = self.seed_anime {
+            if seed == 0 {
+                return Err("invalid seed anime");
+            }
+            if self.blocked_anime.contains(&seed) {
+                return Err("the seed anime is blocked");
+            }
+        }
 
-        LobbyResult::New
+        Ok(())
     }
 
-    fn remove(&self, game_id: String, player_id: String) {
-        let mut lock = self.0.write().unwrap();
-        let Some((p1, p2)) = lock.get_mut(&game_id) else {
-            return;
-        };
+    /// Fills the server defaults in for anything left unset.
+    fn normalized(mut self, config: &Config) -> Self {
+        self.anime_type.get_or_insert_with(|| "tv".to_string());
+        self.turn_secs.get_or_insert(config.turn_secs);
+        if self.teams {
+            self.max_players.get_or_insert(TEAM_SIZE * 2);
+        }
+        self.max_players.get_or_insert(config.max_players);
+        self.max_extends.get_or_insert(config.max_extends);
+        self
+    }
 
-        if *p1 == *player_id {
-            info!(
-                "host left. game ID: {:?}, player ID: {:?}",
-                game_id, player_id
-            );
-            lock.remove(&game_id);
-        } else if *p2 == Some(player_id.clone()) {
-            info!(
-                "guest left. game ID: {:?}, player ID: {:?}",
-                game_id, player_id
-            );
-            *p2 = None;
-        } else {
-            info!(
-                "invalid removal of player. game ID: {:?}, player ID: {:?}",
-                game_id, player_id
-            );
+    /// Whether players may submit an anime of Jikan type `kind`. Anime of
+    /// unknown type only pass when every type does.
+    fn allows_type(&self, kind: Option<&str>) -> bool {
+        self.allowed_types.is_empty()
+            || kind.is_some_and(|kind| {
+                self.allowed_types
+                    .iter()
+                    .any(|t| t.eq_ignore_ascii_case(kind))
+            })
+    }
+
+    /// Jikan path listing the anime a seed can be chosen from.
+    fn seed_path(&self) -> String {
+        let anime_type = self.anime_type.as_deref().unwrap_or("tv");
+
+        if self.genre_id.is_none() && self.min_year.is_none() {
+            return format!("/top/anime?type={}&filter=bypopularity", anime_type);
+        }
+
+        // the top listing can't filter on genre or year, but search can
+        let mut url = format!("/anime?type={}&order_by=members&sort=desc", anime_type);
+        if let Some(genre_id) = self.genre_id {
+            url.push_str(&format!("&genres={}", genre_id));
+        }
+        if let Some(min_year) = self.min_year {
+            url.push_str(&format!("&start_date={}-01-01", min_year));
         }
+
+        url
     }
-}
 
-fn timestamp() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+    /// Packs the ruleset, seed anime included, into a challenge code: a
+    /// version byte, the fields in declaration order and a checksum byte,
+    /// written in base62.
+    fn challenge_code(&self) -> String {
+        let mut bytes = vec![CHALLENGE_VERSION];
+        let flags = [
+            self.no_reused_actors,
+            self.hide_spectator_timer,
+            self.hints,
+            self.teams,
+            self.sudden_death,
+        ];
+        bytes.push(
+            flags
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (i, &on)| acc | (u8::from(on) << i)),
+        );
+        let anime_type = self
+            .anime_type
+            .as_deref()
+            .and_then(|t| ANIME_TYPES.iter().position(|&known| known == t))
+            .map(|i| i as u64 + 1);
+        push_varint(&mut bytes, anime_type.unwrap_or_default());
+        for field in [
+            self.genre_id.map(u64::from),
+            self.min_year.map(u64::from),
+            self.target_chain_length.map(u64::from),
+            self.turn_secs,
+            self.max_players.map(|n| n as u64),
+            self.max_extends.map(u64::from),
+            self.max_game_secs,
+            self.lives.map(u64::from),
+            self.seed_anime.map(u64::from),
+        ] {
+            push_varint(&mut bytes, field.map_or(0, |v| v + 1));
+        }
+        let allowed_types = ANIME_TYPES
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| self.allowed_types.iter().any(|a| a == *t))
+            .fold(0, |acc, (i, _)| acc | (1 << i));
+        push_varint(&mut bytes, allowed_types);
+        push_varint(&mut bytes, self.blocked_anime.len() as u64);
+        for &id in &self.blocked_anime {
+            push_varint(&mut bytes, u64::from(id));
+        }
+        bytes.push(checksum(&bytes));
+
+        base62_encode(&bytes)
+    }
+
+    /// Unpacks a code from `challenge_code`. Codes from another format
+    /// version, mistyped ones and rulesets that no longer validate are all
+    /// refused.
+    fn from_challenge_code(code: &str) -> Result<Self, &'static str> {
+        if code.len() > MAX_CHALLENGE_LEN {
+            return Err("challenge code is too long");
+        }
+        let bytes = base62_decode(code).ok_or("challenge code has invalid characters")?;
+        let (&version, rest) = bytes.split_first().ok_or("empty challenge code")?;
+        if version != CHALLENGE_VERSION {
+            return Err("challenge code is from an unsupported version");
+        }
+        let (&sum, body) = rest.split_last().ok_or("invalid challenge code")?;
+        if checksum(&bytes[..bytes.len() - 1]) != sum {
+            return Err("challenge code is corrupt");
+        }
+
+        let invalid = "invalid challenge code";
+        let (&flags, mut input) = body.split_first().ok_or(invalid)?;
+        let anime_type = match read_varint(&mut input).ok_or(invalid)? {
+            0 => None,
+            i => Some(
+                ANIME_TYPES
+                    .get(i as usize - 1)
+                    .ok_or("unknown anime type")?
+                    .to_string(),
+            ),
+        };
+        // optional fields are stored one up, so zero can mean unset
+        let mut fields = [None; 9];
+        for field in &mut fields {
+            *field = read_varint(&mut input).ok_or(invalid)?.checked_sub(1);
+        }
+        let narrow = |v: Option<u64>| v.map(u32::try_from).transpose().or(Err(invalid));
+        let [genre_id, min_year, target_chain_length, turn_secs, max_players, max_extends, max_game_secs, lives, seed_anime] =
+            fields;
+        let type_mask = read_varint(&mut input).ok_or(invalid)?;
+        if type_mask >> ANIME_TYPES.len() != 0 {
+            return Err("unknown allowed anime type");
+        }
+        let allowed_types = ANIME_TYPES
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| type_mask & (1 << i) != 0)
+            .map(|(_, t)| t.to_string())
+            .collect();
+        let blocked = read_varint(&mut input).ok_or(invalid)? as usize;
+        if blocked > MAX_BLOCKED_ANIME {
+            return Err("too many blocked anime");
+        }
+        let blocked_anime = (0..blocked)
+            .map(|_| {
+                let id = read_varint(&mut input).ok_or(invalid)?;
+                u32::try_from(id).or(Err(invalid))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if !input.is_empty() {
+            return Err("challenge code has trailing data");
+        }
+
+        let config = GameConfig {
+            anime_type,
+            genre_id: narrow(genre_id)?,
+            min_year: min_year
+                .map(u16::try_from)
+                .transpose()
+                .or(Err("invalid year"))?,
+            no_reused_actors: flags & 1 != 0,
+            target_chain_length: narrow(target_chain_length)?,
+            turn_secs,
+            max_players: narrow(max_players)?.map(|n| n as usize),
+            max_extends: narrow(max_extends)?,
+            blocked_anime,
+            hide_spectator_timer: flags & 2 != 0,
+            hints: flags & 4 != 0,
+            max_game_secs,
+            lives: narrow(lives)?,
+            teams: flags & 8 != 0,
+            sudden_death: flags & 16 != 0,
+            seed_anime: narrow(seed_anime)?,
+            allowed_types,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
 }
 
-async fn start_game(s: SocketRef) {
-    info!("game id {:?}", s.extensions.get::<GameId>());
-    let Some(x) = s.extensions.get::<GameId>() else {
-        return;
-    };
+/// Format version written at the front of every challenge code. Bump it
+/// whenever the layout changes so older codes are refused, not misread.
+const CHALLENGE_VERSION: u8 = 2;
 
-    let Ok(data) =
-        reqwest::get("https://api.jikan.moe/v4/top/anime?type=tv&filter=bypopularity").await
-    else {
-        return;
-    };
+/// Longest challenge code accepted, in characters.
+const MAX_CHALLENGE_LEN: usize = 4096;
 
-    let Ok(json) = data.json::<MALResponse>().await else {
-        return;
-    };
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
 
-    let choosen_anime = json.data.choose(&mut rand::thread_rng());
+/// Writes `value` as a LEB128 varint.
+fn push_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
 
-    let Some(choosen_anime) = choosen_anime else {
-        return;
-    };
+/// Reads a LEB128 varint off the front of `input`.
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
 
-    info!(
-        "starting game; anime: {:?}, ts: {}",
-        choosen_anime,
-        timestamp()
-    );
-    s.within(x.0)
-        .emit("start game", &(choosen_anime.mal_id, timestamp()))
-        .ok();
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |acc, &b| acc.wrapping_mul(31).wrapping_add(b))
 }
 
-async fn on_pass(s: SocketRef) {
-    let Some(x) = s.extensions.get::<GameId>() else {
-        return;
-    };
+/// Writes `bytes` as one big-endian number in base62. Leading zero bytes
+/// are lost, which challenge codes avoid by starting with their version.
+fn base62_encode(bytes: &[u8]) -> String {
+    // least significant first
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in bytes {
+        let mut carry = u32::from(byte);
+        for digit in digits.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 62) as u8;
+            carry /= 62;
+        }
+        while carry > 0 {
+            digits.push((carry % 62) as u8);
+            carry /= 62;
+        }
+    }
 
-    s.within(x.0).emit("pass", &timestamp()).ok();
+    digits
+        .iter()
+        .rev()
+        .map(|&d| char::from(BASE62[d as usize]))
+        .collect()
 }
 
-fn on_connect(socket: SocketRef, Data(data): Data<Value>) {
-    info!(ns = socket.ns(), ?socket.id, "Socket.IO connected");
-    socket.emit("auth", &data).ok();
+fn base62_decode(code: &str) -> Option<Vec<u8>> {
+    // least significant first
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in code.bytes() {
+        let mut carry = BASE62.iter().position(|&d| d == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 62;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    bytes.reverse();
 
-    socket.on(
-        "join_game",
-        |s: SocketRef, Data::<EventData>(data), state: State<Lobby>, ack: AckSender| {
-            if s.extensions.get::<PlayerId>().is_some() {
-                return;
-            }
+    Some(bytes)
+}
 
-            s.extensions.insert(PlayerId(data.player_id.clone()));
-            s.extensions.insert(GameId(data.game_id.clone()));
+#[derive(Deserialize, Serialize, Debug)]
+struct EventData {
+    game_id: String,
+    /// Left empty by guests, who get one generated for them.
+    #[serde(default)]
+    player_id: String,
+    /// Only honoured for the player who opens the lobby.
+    #[serde(default)]
+    config: Option<GameConfig>,
+    /// Name shown to other players instead of the id.
+    #[serde(default)]
+    display_name: Option<String>,
+    /// Team the player would like in a team game. Granted while it has room.
+    #[serde(default)]
+    team: Option<Team>,
+    /// Code from `POST /challenge`, used in place of `config`. Like it, only
+    /// honoured for the player who opens the lobby.
+    #[serde(default)]
+    challenge: Option<String>,
+}
 
-            let res = state.insert(data.game_id.clone(), data.player_id.clone());
-            info!("lobby {:?}", state.0);
+impl EventData {
+    /// Checks the ids are safe to use as room names and to echo to peers.
+    fn validate(&self) -> Result<(), &'static str> {
+        if !is_valid_id(&self.game_id) {
+            return Err("invalid game id");
+        }
+        if !is_valid_id(&self.player_id) {
+            return Err("invalid player id");
+        }
 
-            match res {
-                LobbyResult::New => {
-                    ack.send("ok_new").ok();
-                }
-                LobbyResult::Paired(host_id) => {
-                    ack.send(&("ok_paired", host_id)).ok();
-                }
-                LobbyResult::Full => {
-                    info!("lobby is full");
-                    ack.send("room is full").ok();
-                    return;
-                }
-            }
+        Ok(())
+    }
+}
 
-            let _ = s.join(data.game_id.clone());
-            s.to(data.game_id.clone())
-                .emit("player joined", &data.player_id.clone())
-                .ok();
-        },
-    );
+/// Cleans up a requested display name: keeps letters, digits, spaces and
+/// `-_.'`, squeezes runs of spaces and cuts it to `MAX_NAME_LEN` characters.
+/// Returns `None` if nothing is left.
+fn sanitize_display_name(raw: &str) -> Option<String> {
+    let kept: String = raw
+        .chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.' | '\''))
+        .collect();
+    let name: String = kept
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(MAX_NAME_LEN)
+        .collect();
+    let name = name.trim_end();
 
-    socket.on("start game", start_game);
-    socket.on("pass", on_pass);
-    socket.on("extend", |s: SocketRef| {
-        let Some(x) = s.extensions.get::<GameId>() else {
-            return;
-        };
+    (!name.is_empty()).then(|| name.to_string())
+}
 
-        s.within(x.0).emit("extend", &()).ok();
-    });
+/// Whether `id` is 1 to `MAX_ID_LEN` characters of ASCII letters, digits,
+/// `-` or `_`.
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
 
-    socket.on("send anime", |s: SocketRef, Data::<i64>(data)| {
-        let Some(x) = s.extensions.get::<GameId>() else {
-            return;
-        };
+#[derive(Deserialize, Serialize, Debug)]
+struct RejoinData {
+    game_id: String,
+    token: String,
+}
 
-        s.within(x.0).emit("next anime", &(data, timestamp())).ok();
-    });
+/// Either a bare game id, or one with the token a dropped spectator was
+/// given so they resume their seat instead of taking another.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum SpectateData {
+    GameId(String),
+    Resume {
+        game_id: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+}
 
-    socket.on("message-with-ack", |Data::<Value>(data), ack: AckSender| {
-        info!(?data, "Received event");
-        ack.send(&("replied: ".to_owned() + data.as_str().unwrap()))
-            .ok();
-    });
+impl SpectateData {
+    fn into_parts(self) -> (String, Option<String>) {
+        match self {
+            SpectateData::GameId(game_id) => (game_id, None),
+            SpectateData::Resume { game_id, token } => (game_id, token),
+        }
+    }
+}
 
-    socket.on_disconnect(|s: SocketRef, state: State<Lobby>| {
-        let Some(g) = s.extensions.get::<GameId>() else {
-            info!("Disconnected with no game ID");
-            return;
-        };
+/// Sent back to a new spectator; pass `token` to `spectate_game` or
+/// `rejoin_game` to watch again after a drop.
+#[derive(Serialize, Debug)]
+struct SpectateReply {
+    token: String,
+}
 
-        let Some(p) = s.extensions.get::<PlayerId>() else {
-            info!("Disconnected with no player ID");
-            return;
-        };
+#[derive(Serialize, Deserialize, Debug)]
+struct ImageUrls {
+    image_url: Option<String>,
+}
 
-        info!("Disconnected with game ID: {:?}, player ID: {:?}", g, p);
-        state.remove(g.0.clone(), p.0);
+#[derive(Serialize, Deserialize, Debug)]
+struct Images {
+    jpg: ImageUrls,
+}
 
-        s.to(g.0).emit("player disconnected", &()).ok();
-    });
+#[derive(Serialize, Deserialize, Debug)]
+struct Anime {
+    mal_id: u32,
+    title: String,
+    title_english: Option<String>,
+    images: Images,
+    /// "TV", "Movie" and so on.
+    #[serde(rename = "type", default)]
+    kind: Option<String>,
 }
 
-async fn create_game() -> String {
-    nanoid!()
+#[derive(Serialize, Deserialize, Debug)]
+struct MALResponse {
+    data: Vec<Anime>,
+    #[serde(default)]
+    pagination: Option<Pagination>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing::subscriber::set_global_default(FmtSubscriber::default())?;
+#[derive(Serialize, Deserialize, Debug)]
+struct Pagination {
+    has_next_page: bool,
+}
 
-    let (layer, io) = SocketIo::builder()
-        .with_state(Lobby::default())
-        .build_layer();
+#[derive(Serialize, Deserialize, Debug)]
+struct AnimeResponse {
+    data: Anime,
+}
 
-    io.ns("/", on_connect);
+/// What clients need to display an anime without asking Jikan themselves.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AnimeInfo {
+    mal_id: u32,
+    title: String,
+    title_english: Option<String>,
+    image_url: Option<String>,
+    /// Jikan's type for the anime, such as "TV" or "Movie".
+    #[serde(default)]
+    anime_type: Option<String>,
+}
 
-    let cors = CorsLayer::new().allow_origin(
-        env::var("FRONTEND_URL")
-            .unwrap_or("".to_string())
-            .parse::<HeaderValue>()
-            .unwrap(),
-    );
+impl From<Anime> for AnimeInfo {
+    fn from(anime: Anime) -> Self {
+        AnimeInfo {
+            mal_id: anime.mal_id,
+            title: anime.title,
+            title_english: anime.title_english,
+            image_url: anime.images.jpg.image_url,
+            anime_type: anime.kind,
+        }
+    }
+}
 
-    let app = axum::Router::new()
-        .route("/game", axum::routing::post(create_game))
-        .route(
-            "/healthz",
-            axum::routing::get(|| async { StatusCode::NO_CONTENT }),
-        )
-        .layer(layer)
-        .layer(cors);
+#[derive(Serialize, Deserialize, Debug)]
+struct Person {
+    mal_id: u32,
+    name: String,
+}
 
-    info!("Starting server");
+#[derive(Serialize, Deserialize, Debug)]
+struct VoiceActor {
+    person: Person,
+    language: String,
+}
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Character {
+    mal_id: u32,
+    name: String,
+}
 
-    Ok(())
+#[derive(Serialize, Deserialize, Debug)]
+struct CharacterRole {
+    character: Character,
+    voice_actors: Vec<VoiceActor>,
+}
+
+/// A Japanese voice actor and the characters they voiced in one anime.
+#[derive(Debug, Clone)]
+struct Credit {
+    name: String,
+    characters: Vec<Character>,
+}
+
+/// A voice actor linking two consecutive anime in the chain.
+#[derive(Serialize, Debug, Clone)]
+struct Connection {
+    mal_id: u32,
+    name: String,
+    /// Characters they voiced in the previous anime.
+    from_characters: Vec<Character>,
+    /// Characters they voiced in the submitted anime.
+    to_characters: Vec<Character>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CharactersResponse {
+    data: Vec<CharacterRole>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PersonVoicesResponse {
+    data: Vec<PersonVoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PersonVoice {
+    anime: VoicedAnime,
+}
+
+/// An anime a person has a voice role in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct VoicedAnime {
+    mal_id: u32,
+    title: String,
+}
+
+/// Game settings, read from the environment at startup.
+#[derive(Clone, Debug)]
+struct Config {
+    turn_secs: u64,
+    extend_secs: u64,
+    /// Times a single turn may be extended.
+    max_extends: u32,
+    max_players: usize,
+    top_anime_ttl: Duration,
+    lobby_ttl: Duration,
+    /// How long a disconnected player keeps their slot before being evicted.
+    reconnect_grace: Duration,
+    /// Spectators a single game may have, 0 for no limit.
+    max_spectators: usize,
+    /// Pages of the anime listing that seed pools are drawn from.
+    seed_pool_pages: u32,
+    /// Events per second a socket may send, 0 for no limit.
+    send_anime_rate: u32,
+    start_game_rate: u32,
+    /// Whether spectators may post in the game chat.
+    spectator_chat: bool,
+    seed_source: SeedSource,
+    /// How long a whole Jikan request may take, response body included.
+    jikan_timeout: Duration,
+    /// Entries each Jikan response cache holds before dropping the least
+    /// recently used.
+    jikan_cache_size: usize,
+    /// How long a cached Jikan response is trusted.
+    jikan_cache_ttl: Duration,
+    /// How often sockets are pinged; zero disables the heartbeat.
+    heartbeat_interval: Duration,
+    /// Consecutive unanswered pings after which a socket is dropped.
+    heartbeat_misses: u32,
+    /// How often a turn in progress is re-announced as a `state snapshot`;
+    /// zero disables them.
+    snapshot_interval: Duration,
+    /// Passes a player may make before passing again loses the game; zero
+    /// for no limit.
+    max_passes: u32,
+    /// Points taken off a player's score each time they pass.
+    pass_penalty: u32,
+    /// Times the host may redraw the seed anime per game.
+    max_rerolls: u32,
+    /// Timeouts each player survives, minus one: the last life lost ends
+    /// the game.
+    lives: u32,
+    /// Seconds after which a game ends on score, 0 for no limit.
+    max_game_secs: u64,
+    first_turn: FirstTurn,
+    /// Characters and length of generated game codes.
+    game_code_alphabet: Arc<[char]>,
+    game_code_length: usize,
+    /// Anime left out of seed picks and refused as moves in every game.
+    blocked_anime: HashSet<u32>,
+}
+
+impl Config {
+    /// Reads the settings, failing on values that are set but can't be
+    /// parsed or make no sense rather than quietly using the default.
+    fn from_env() -> Result<Self, String> {
+        fn var<T: std::str::FromStr>(key: &str, default: T) -> Result<T, String>
+        where
+            T::Err: fmt::Display,
+        {
+            match env::var(key) {
+                Ok(v) => v
+                    .trim()
+                    .parse::<T>()
+                    .map_err(|e| format!("invalid {} {:?}: {}", key, v, e)),
+                Err(_) => Ok(default),
+            }
+        }
+
+        let config = Config {
+            turn_secs: var("TURN_SECS", 60)?,
+            extend_secs: var("EXTEND_SECS", 30)?,
+            max_extends: var("MAX_EXTENDS", 2)?,
+            max_players: var("MAX_PLAYERS", 2)?,
+            top_anime_ttl: Duration::from_secs(var("TOP_ANIME_TTL_SECS", 600)?),
+            lobby_ttl: Duration::from_secs(var("LOBBY_TTL_SECS", 3600)?),
+            reconnect_grace: Duration::from_secs(var("RECONNECT_GRACE_SECS", 30)?),
+            max_spectators: var("MAX_SPECTATORS", 50)?,
+            seed_pool_pages: var("SEED_POOL_PAGES", 4)?,
+            send_anime_rate: var("SEND_ANIME_RATE", 5)?,
+            start_game_rate: var("START_GAME_RATE", 5)?,
+            spectator_chat: var::<u32>("SPECTATOR_CHAT", 0)? != 0,
+            jikan_timeout: Duration::from_secs(var("JIKAN_TIMEOUT_SECS", 10)?),
+            jikan_cache_size: var("JIKAN_CACHE_SIZE", 5000)?,
+            jikan_cache_ttl: Duration::from_secs(var("JIKAN_CACHE_TTL_SECS", 86400)?),
+            heartbeat_interval: Duration::from_secs(var("HEARTBEAT_SECS", 15)?),
+            snapshot_interval: Duration::from_secs(var("SNAPSHOT_SECS", 15)?),
+            heartbeat_misses: var("HEARTBEAT_MISSES", 3)?,
+            max_passes: var("MAX_PASSES", 3)?,
+            pass_penalty: var("PASS_PENALTY", 0)?,
+            max_rerolls: var("MAX_REROLLS", 2)?,
+            lives: var("LIVES", 3)?,
+            max_game_secs: var("MAX_GAME_SECS", 0)?,
+            seed_source: if var::<u32>("DAILY_SEED", 0)? != 0 {
+                SeedSource::Daily
+            } else {
+                SeedSource::Random
+            },
+            first_turn: match env::var("FIRST_TURN").as_deref() {
+                Ok("host") | Err(_) => FirstTurn::Host,
+                Ok("random") => FirstTurn::Random,
+                Ok(other) => {
+                    return Err(format!(
+                        "invalid FIRST_TURN {:?}: expected host or random",
+                        other
+                    ))
+                }
+            },
+            game_code_alphabet: game_code_alphabet(
+                &env::var("GAME_CODE_ALPHABET").unwrap_or(GAME_CODE_ALPHABET.to_string()),
+            )?,
+            game_code_length: var("GAME_CODE_LENGTH", 6)?,
+            blocked_anime: blocked_anime(&env::var("BLOCKED_ANIME").unwrap_or_default())?,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.turn_secs != 0 && !(5..=3600).contains(&self.turn_secs) {
+            return Err("TURN_SECS must be 0 or between 5 and 3600".to_string());
+        }
+
+        if !(1..=3600).contains(&self.extend_secs) {
+            return Err("EXTEND_SECS must be between 1 and 3600".to_string());
+        }
+
+        if self.max_extends > 10 {
+            return Err("MAX_EXTENDS must be at most 10".to_string());
+        }
+
+        if !(2..=6).contains(&self.max_players) {
+            return Err("MAX_PLAYERS must be between 2 and 6".to_string());
+        }
+
+        if self.lobby_ttl < LOBBY_SWEEP_INTERVAL {
+            return Err(format!(
+                "LOBBY_TTL_SECS must be at least {}",
+                LOBBY_SWEEP_INTERVAL.as_secs()
+            ));
+        }
+
+        if self.max_game_secs != 0 && !(60..=86400).contains(&self.max_game_secs) {
+            return Err("MAX_GAME_SECS must be 0 or between 60 and 86400".to_string());
+        }
+
+        if !(1..=10).contains(&self.lives) {
+            return Err("LIVES must be between 1 and 10".to_string());
+        }
+
+        if !(1..=20).contains(&self.seed_pool_pages) {
+            return Err("SEED_POOL_PAGES must be between 1 and 20".to_string());
+        }
+
+        if self.reconnect_grace > Duration::from_secs(3600) {
+            return Err("RECONNECT_GRACE_SECS must be at most 3600".to_string());
+        }
+
+        if self.jikan_cache_size == 0 {
+            return Err("JIKAN_CACHE_SIZE must be at least 1".to_string());
+        }
+
+        if self.jikan_cache_ttl.is_zero() {
+            return Err("JIKAN_CACHE_TTL_SECS must be at least 1".to_string());
+        }
+
+        if self.heartbeat_misses == 0 {
+            return Err("HEARTBEAT_MISSES must be at least 1".to_string());
+        }
+
+        if !(4..=MAX_ID_LEN).contains(&self.game_code_length) {
+            return Err(format!(
+                "GAME_CODE_LENGTH must be between 4 and {}",
+                MAX_ID_LEN
+            ));
+        }
+
+        if self.max_spectators > 10_000 {
+            return Err("MAX_SPECTATORS must be at most 10000".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Whether `mal_id` is off limits in a game set up with `game`.
+    fn is_blocked(&self, game: &GameConfig, mal_id: u32) -> bool {
+        self.blocked_anime.contains(&mal_id) || game.blocked_anime.contains(&mal_id)
+    }
+
+    /// The HTTP client for Jikan calls, held to `jikan_timeout`.
+    fn jikan_client(&self) -> reqwest::Result<reqwest::Client> {
+        reqwest::Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .connect_timeout(JIKAN_CONNECT_TIMEOUT)
+            .timeout(self.jikan_timeout)
+            .build()
+    }
+
+    /// A fresh random game code. Callers should check it isn't taken.
+    fn game_code(&self) -> String {
+        let length = self.game_code_length;
+        nanoid!(length, &self.game_code_alphabet)
+    }
+}
+
+/// Parses a comma-separated list of MAL ids.
+fn blocked_anime(raw: &str) -> Result<HashSet<u32>, String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map_err(|e| format!("invalid BLOCKED_ANIME entry {:?}: {}", id, e))
+        })
+        .collect()
+}
+
+/// Parses a game code alphabet, refusing one with characters that aren't
+/// valid in ids or too few distinct ones to be useful.
+fn game_code_alphabet(raw: &str) -> Result<Arc<[char]>, String> {
+    let mut chars: Vec<char> = raw.chars().collect();
+    chars.sort_unstable();
+    chars.dedup();
+
+    if chars.len() < 2 || !is_valid_id(&chars.iter().collect::<String>()) {
+        return Err(format!(
+            "invalid GAME_CODE_ALPHABET {:?}: needs at least 2 distinct letters, digits, - or _",
+            raw
+        ));
+    }
+
+    Ok(chars.into())
+}
+
+/// Where seed anime picks get their randomness from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SeedSource {
+    Random,
+    /// Seeded from the UTC date, so every game started on the same day (with
+    /// the same pool) gets the same seed anime.
+    Daily,
+}
+
+impl SeedSource {
+    fn rng(self) -> StdRng {
+        match self {
+            SeedSource::Random => StdRng::from_entropy(),
+            SeedSource::Daily => StdRng::seed_from_u64(timestamp() / 86_400),
+        }
+    }
+}
+
+/// Who gets the first turn of a game.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FirstTurn {
+    Host,
+    /// A coin flip between the players.
+    Random,
+}
+
+impl FirstTurn {
+    fn choose(self, players: &[PlayerId]) -> Option<&PlayerId> {
+        match self {
+            FirstTurn::Host => players.first(),
+            FirstTurn::Random => players.choose(&mut StdRng::from_entropy()),
+        }
+    }
+}
+
+/// Picks the seed anime from a pool.
+fn choose_anime<R: Rng + ?Sized>(ids: &[u32], rng: &mut R) -> Option<u32> {
+    ids.choose(rng).copied()
+}
+
+/// Token bucket refilling at `rate` tokens per second, up to `rate` tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn try_take(&mut self, rate: u32) -> bool {
+        let rate = f64::from(rate);
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The span a socket's event spans hang off, opened on connect.
+#[derive(Clone, Debug)]
+struct SocketSpan(Span);
+
+/// A span for handling `event` on `s`, carrying the game and player the
+/// socket is in (if any) under its connection's span.
+fn event_span(s: &SocketRef, event: &'static str) -> Span {
+    let parent = s
+        .extensions
+        .get::<SocketSpan>()
+        .and_then(|span| span.0.id());
+    let span = info_span!(
+        parent: parent,
+        "event",
+        event,
+        game_id = tracing::field::Empty,
+        player_id = tracing::field::Empty,
+    );
+    if let Some(g) = s.extensions.get::<GameId>() {
+        span.record("game_id", g.0.as_str());
+    }
+    if let Some(p) = s.extensions.get::<PlayerId>() {
+        span.record("player_id", p.0.as_str());
+    }
+
+    span
+}
+
+/// Per-socket event buckets, kept in the socket's extensions so they are
+/// dropped along with the socket on disconnect.
+#[derive(Clone, Default, Debug)]
+struct RateLimiter(Arc<Mutex<HashMap<&'static str, TokenBucket>>>);
+
+impl RateLimiter {
+    /// Whether another `event` fits within `rate` per second.
+    fn allow(&self, event: &'static str, rate: u32) -> bool {
+        if rate == 0 {
+            return true;
+        }
+
+        let mut lock = self.0.write_or_recover();
+        lock.entry(event)
+            .or_insert_with(|| TokenBucket {
+                tokens: f64::from(rate),
+                refilled_at: Instant::now(),
+            })
+            .try_take(rate)
+    }
+}
+
+/// Anime ids along with when they were fetched, keyed by the path listing them.
+type CachedIds = HashMap<String, (Vec<u32>, Instant)>;
+
+/// Per-id cache of resolved anime details.
+#[derive(Clone, Debug)]
+struct AnimeInfoCache {
+    entries: Arc<RwLock<HashMap<u32, AnimeInfo>>>,
+    /// Ids Jikan answered 404 for, with when it did.
+    missing: Arc<RwLock<HashMap<u32, Instant>>>,
+    jikan: Jikan,
+}
+
+impl AnimeInfoCache {
+    fn new(jikan: Jikan) -> Self {
+        AnimeInfoCache {
+            entries: Arc::default(),
+            missing: Arc::default(),
+            jikan,
+        }
+    }
+
+    fn insert(&self, info: AnimeInfo) {
+        let mut lock = self.entries.write_or_recover();
+        lock.insert(info.mal_id, info);
+    }
+
+    /// Returns the details for `mal_id`, fetching them from Jikan on a miss.
+    async fn get(&self, mal_id: u32) -> Result<AnimeInfo, JikanError> {
+        if let Some(info) = self.entries.read_or_recover().get(&mal_id) {
+            return Ok(info.clone());
+        }
+
+        let known_missing = self
+            .missing
+            .read_or_recover()
+            .get(&mal_id)
+            .is_some_and(|at| at.elapsed() < UNKNOWN_ANIME_TTL);
+        if known_missing {
+            return Err(JikanError::Status(StatusCode::NOT_FOUND));
+        }
+
+        let path = format!("/anime/{}", mal_id);
+        let res = self.jikan.get::<AnimeResponse>(&path).await;
+        let info = match res {
+            Ok(res) => AnimeInfo::from(res.data),
+            Err(e) => {
+                if e.is_not_found() {
+                    let mut lock = self.missing.write_or_recover();
+                    lock.retain(|_, at| at.elapsed() < UNKNOWN_ANIME_TTL);
+                    lock.insert(mal_id, Instant::now());
+                }
+                return Err(e);
+            }
+        };
+        self.insert(info.clone());
+
+        Ok(info)
+    }
+}
+
+/// Shared cache of the anime id pools that games are seeded from.
+#[derive(Clone, Debug)]
+struct TopAnimeCache {
+    ttl: Duration,
+    /// Pages of each listing that go into its pool.
+    pages: u32,
+    entries: Arc<tokio::sync::RwLock<CachedIds>>,
+    /// Filled with the details that come along with the top anime listing.
+    info: AnimeInfoCache,
+}
+
+impl TopAnimeCache {
+    fn new(ttl: Duration, pages: u32, info: AnimeInfoCache) -> Self {
+        TopAnimeCache {
+            ttl,
+            pages,
+            entries: Arc::default(),
+            info,
+        }
+    }
+
+    /// Returns the ids listed at `path`, refetching them from Jikan once they
+    /// go stale.
+    async fn get(&self, path: &str) -> Result<Vec<u32>, JikanError> {
+        let fresh = |entries: &CachedIds| {
+            entries
+                .get(path)
+                .filter(|(_, fetched)| fetched.elapsed() < self.ttl)
+                .map(|(ids, _)| ids.clone())
+        };
+
+        if let Some(ids) = fresh(&*self.entries.read().await) {
+            return Ok(ids);
+        }
+
+        // holding the write lock across the fetch makes concurrent callers
+        // wait for this refresh instead of starting their own
+        let mut lock = self.entries.write().await;
+        if let Some(ids) = fresh(&lock) {
+            return Ok(ids);
+        }
+
+        info!("refreshing anime pool cache for {}", path);
+        let mut ids = Vec::new();
+        for page in 1..=self.pages {
+            if page > 1 {
+                tokio::time::sleep(JIKAN_PAGE_DELAY).await;
+            }
+
+            let url = format!("{}&page={}", path, page);
+            let json = match self.info.jikan.get::<MALResponse>(&url).await {
+                Ok(json) => json,
+                Err(e) if page == 1 => return Err(e),
+                // a partial pool still has more variety than none
+                Err(e) => {
+                    warn!("keeping {} pages of {}: {}", page - 1, path, e);
+                    break;
+                }
+            };
+
+            let more = json.pagination.as_ref().is_some_and(|p| p.has_next_page);
+            for anime in json.data {
+                if !ids.contains(&anime.mal_id) {
+                    ids.push(anime.mal_id);
+                }
+                self.info.insert(anime.into());
+            }
+            if !more {
+                break;
+            }
+        }
+
+        lock.insert(path.to_string(), (ids.clone(), Instant::now()));
+
+        Ok(ids)
+    }
+}
+
+/// One of the two sides in a team game.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+enum Team {
+    Red,
+    Blue,
+}
+
+/// Splits `players` into two teams as even as their number allows. Each
+/// player gets the team they asked for, in join order, until it is full.
+fn assign_teams(
+    players: &[PlayerId],
+    wanted: impl Fn(&PlayerId) -> Option<Team>,
+) -> HashMap<PlayerId, Team> {
+    let room = players.len().div_ceil(2);
+    let mut teams = HashMap::new();
+    let size =
+        |teams: &HashMap<PlayerId, Team>, team| teams.values().filter(|t| **t == team).count();
+
+    for player in players {
+        if let Some(team) = wanted(player).filter(|t| size(&teams, *t) < room) {
+            teams.insert(player.clone(), team);
+        }
+    }
+    for player in players {
+        if !teams.contains_key(player) {
+            let team = if size(&teams, Team::Red) <= size(&teams, Team::Blue) {
+                Team::Red
+            } else {
+                Team::Blue
+            };
+            teams.insert(player.clone(), team);
+        }
+    }
+
+    teams
+}
+
+/// Why a game ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum EndReason {
+    Timeout,
+    Forfeit,
+    PlayerLeft,
+    PassLimit,
+    TargetReached,
+    /// The game ran for as long as it was allowed to.
+    TimeLimit,
+    /// Sudden death settled a tied game, or every contender failed and it
+    /// was drawn.
+    SuddenDeath,
+}
+
+/// Overtime after a game ended tied: the contenders take one turn each, and
+/// the first to make a move wins.
+#[derive(Serialize, Debug, Clone)]
+struct Overtime {
+    /// Players tied for the lead, in the order they take their turns.
+    contenders: Vec<PlayerId>,
+    /// Contenders whose overtime turn ran out or who passed it.
+    failed: Vec<PlayerId>,
+}
+
+/// Lets go of the task in `slot` without aborting it, for a task clearing
+/// its own slot: it is about to finish on its own, and aborting it from
+/// inside would cut short whatever it still has to send.
+fn release_task(slot: &mut Option<AbortHandle>) {
+    slot.take();
+}
+
+/// Where a game is in its life. Moves from `Lobby` to `InProgress` exactly
+/// once per game, the moment a start is claimed.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+enum Phase {
+    #[default]
+    Lobby,
+    InProgress,
+    Finished,
+}
+
+/// Server-side record of a game in progress.
+#[derive(Serialize, Debug, Clone, Default)]
+struct GameState {
+    /// MAL ids played so far, starting with the seed anime.
+    chain: Vec<u32>,
+    current_turn: Option<PlayerId>,
+    /// Total points scored, the sum of `scores`.
+    score: u32,
+    /// Points per player. The top score wins a game that reaches its target
+    /// length or time limit, and a tie for it goes to sudden death. Games
+    /// lost by timing out, passing too often, forfeiting or leaving go to
+    /// whoever outlasts the others, whatever the scores.
+    scores: HashMap<PlayerId, u32>,
+    /// Times the current turn has been extended.
+    extends_used: u32,
+    /// How many times each player has passed their turn.
+    passes: HashMap<PlayerId, u32>,
+    /// Voice actors who have linked two anime in the chain so far.
+    used_actors: Vec<u32>,
+    /// Voice actors behind each link: `links[i]` joins `chain[i]` to
+    /// `chain[i + 1]`.
+    links: Vec<Vec<u32>>,
+    /// Unix seconds at which the current turn times out.
+    deadline: Option<u64>,
+    /// Seconds left on the turn while the timer is paused for a reconnect.
+    paused_secs: Option<u64>,
+    /// Whether turns run out at all; untimed games never have a deadline.
+    timed: bool,
+    /// Seconds each turn gets, zero in untimed games.
+    #[serde(skip)]
+    turn_secs: u64,
+    /// Lives each player has left. Running out of time costs one, and
+    /// losing the last loses the game.
+    lives: HashMap<PlayerId, u32>,
+    /// Each player's side in a team game; empty otherwise. Players who join
+    /// once the game is underway have no team.
+    teams: HashMap<PlayerId, Team>,
+    started_at: Option<u64>,
+    /// Unix seconds at which the game ends on score, if it has a time limit.
+    ends_at: Option<u64>,
+    phase: Phase,
+    winner: Option<PlayerId>,
+    /// The winner's side in a team game.
+    winning_team: Option<Team>,
+    end_reason: Option<EndReason>,
+    /// Sudden death in progress, or the one that ended the game.
+    overtime: Option<Overtime>,
+    /// The latest move, while it is still the latest thing that happened and
+    /// so can be undone.
+    #[serde(skip)]
+    last_move: Option<LastMove>,
+    /// Everything that happened on each turn, oldest first. Only ever
+    /// appended to, so an undo shows up as its own entry.
+    history: Vec<TurnRecord>,
+    /// Unix seconds at which the current turn began.
+    #[serde(skip)]
+    turn_started_at: Option<u64>,
+    /// Number of the latest event broadcast to the room. Keeps counting
+    /// across rematches so clients can always spot a gap.
+    seq: u64,
+    #[serde(skip)]
+    timer: Option<AbortHandle>,
+    /// Ends the game at `ends_at`.
+    #[serde(skip)]
+    clock: Option<AbortHandle>,
+    /// Sends the current turn's `state snapshot`s.
+    #[serde(skip)]
+    snapshots: Option<AbortHandle>,
+}
+
+impl GameState {
+    /// Passes the turn to `player`, with a fresh allowance of extensions.
+    fn hand_turn(&mut self, player: Option<PlayerId>) {
+        self.current_turn = player;
+        self.extends_used = 0;
+        self.turn_started_at = Some(timestamp());
+    }
+
+    /// Appends to the history, timing the entry from the start of the
+    /// current turn. Call before handing the turn over.
+    fn log(
+        &mut self,
+        player: &PlayerId,
+        action: TurnAction,
+        mal_id: Option<u32>,
+        score_delta: i64,
+    ) {
+        if self.history.len() >= MAX_HISTORY {
+            return;
+        }
+
+        let at = timestamp();
+        let actors = match action {
+            TurnAction::Move => self.links.last().cloned().unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        self.history.push(TurnRecord {
+            player: player.clone(),
+            action,
+            mal_id,
+            actors,
+            score_delta,
+            at,
+            took_secs: self
+                .turn_started_at
+                .map_or(0, |start| at.saturating_sub(start)),
+        });
+    }
+
+    /// Points per team, summed from its players' scores.
+    fn team_scores(&self) -> HashMap<Team, u32> {
+        let mut totals = HashMap::new();
+        for (player, team) in &self.teams {
+            *totals.entry(*team).or_default() +=
+                self.scores.get(player).copied().unwrap_or_default();
+        }
+        totals
+    }
+
+    /// Aborts the turn timer, the snapshot task and the game clock.
+    fn stop_tasks(&mut self) {
+        for task in [&mut self.timer, &mut self.snapshots, &mut self.clock] {
+            if let Some(task) = task.take() {
+                task.abort();
+            }
+        }
+    }
+
+    /// This state with the turn timer left out, for spectators of games that
+    /// hide it from them.
+    fn without_timer(&self) -> Self {
+        GameState {
+            deadline: None,
+            paused_secs: None,
+            ..self.clone()
+        }
+    }
+
+    /// A blank game that carries on this one's sequence numbers.
+    fn fresh(&self) -> Self {
+        GameState {
+            seq: self.seq,
+            ..Default::default()
+        }
+    }
+
+    /// Whether moves are being played: past picking the seed and not over.
+    fn in_progress(&self) -> bool {
+        self.phase == Phase::InProgress && !self.chain.is_empty()
+    }
+
+    fn status(&self) -> &'static str {
+        match self.phase {
+            Phase::Finished => "finished",
+            // still "lobby" while the seed anime is being picked
+            Phase::InProgress if !self.chain.is_empty() => "in_progress",
+            _ => "lobby",
+        }
+    }
+
+    /// Whether `mal_id` is already part of the chain, seed included.
+    fn has_played(&self, mal_id: u32) -> bool {
+        self.chain.contains(&mal_id)
+    }
+}
+
+/// A finished game as it is written to the [`GameStore`].
+#[derive(Serialize, Deserialize, Debug)]
+struct GameRecord {
+    game_id: String,
+    players: Vec<PlayerId>,
+    chain: Vec<u32>,
+    winner: Option<PlayerId>,
+    #[serde(default)]
+    end_reason: Option<EndReason>,
+    score: u32,
+    created_at: u64,
+    started_at: Option<u64>,
+    finished_at: u64,
+    #[serde(default)]
+    history: Vec<TurnRecord>,
+    #[serde(default)]
+    teams: HashMap<PlayerId, Team>,
+    #[serde(default)]
+    winning_team: Option<Team>,
+}
+
+/// Somewhere finished games are kept once they leave the live lobby.
+trait GameStore: fmt::Debug + Send + Sync {
+    fn save(&self, record: &GameRecord) -> io::Result<()>;
+
+    /// Every game saved so far, oldest first.
+    fn load(&self) -> io::Result<Vec<GameRecord>>;
+}
+
+/// Appends each finished game as one JSON line to a file.
+#[derive(Debug)]
+struct JsonlStore {
+    path: PathBuf,
+    /// Keeps concurrent writers from interleaving their lines.
+    file: Mutex<()>,
+}
+
+impl JsonlStore {
+    fn new(path: impl Into<PathBuf>) -> Self {
+        JsonlStore {
+            path: path.into(),
+            file: Mutex::new(()),
+        }
+    }
+}
+
+impl GameStore for JsonlStore {
+    fn save(&self, record: &GameRecord) -> io::Result<()> {
+        let line = serde_json::to_string(record)?;
+        let _guard = self.file.write_or_recover();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        writeln!(file, "{}", line)
+    }
+
+    fn load(&self) -> io::Result<Vec<GameRecord>> {
+        let _guard = self.file.write_or_recover();
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        // a torn last line from a crash shouldn't hide every other game
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+/// A player's record across finished games.
+#[derive(Serialize, Debug, Clone, Default)]
+struct PlayerStats {
+    games_played: u32,
+    wins: u32,
+    /// Games someone else won; games that ended without a winner count as
+    /// neither a win nor a loss.
+    losses: u32,
+    average_chain_length: f64,
+}
+
+/// Win/loss records keyed by player id, built from finished games.
+#[derive(Clone, Default, Debug)]
+struct StatsBook(Arc<RwLock<HashMap<PlayerId, PlayerStats>>>);
+
+impl StatsBook {
+    fn record(&self, game: &GameRecord) {
+        let mut lock = self.0.write_or_recover();
+        for player in &game.players {
+            let stats = lock.entry(player.clone()).or_default();
+            let total = stats.average_chain_length * f64::from(stats.games_played);
+            stats.games_played += 1;
+            stats.average_chain_length =
+                (total + game.chain.len() as f64) / f64::from(stats.games_played);
+
+            let team = game.teams.get(player);
+            match &game.winner {
+                Some(winner) if winner == player => stats.wins += 1,
+                Some(_) if team.is_some() && team == game.winning_team.as_ref() => stats.wins += 1,
+                Some(_) => stats.losses += 1,
+                None => {}
+            }
+        }
+    }
+
+    fn get(&self, player: &PlayerId) -> Option<PlayerStats> {
+        self.0.read_or_recover().get(player).cloned()
+    }
+}
+
+/// A player's claim on their lobby slot across socket reconnects.
+#[derive(Debug)]
+struct Session {
+    token: String,
+    display_name: String,
+    /// Pending eviction while the player is disconnected.
+    eviction: Option<AbortHandle>,
+    /// Team asked for on joining, settled when the game starts.
+    team: Option<Team>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum TurnAction {
+    Move,
+    Pass,
+    Timeout,
+    /// The players took back the `player`'s latest move.
+    Undo,
+}
+
+/// One entry in a game's turn history.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct TurnRecord {
+    player: PlayerId,
+    action: TurnAction,
+    /// The anime played, or taken back by an undo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mal_id: Option<u32>,
+    /// Voice actors the move linked through.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    actors: Vec<u32>,
+    score_delta: i64,
+    /// Unix seconds at which it happened.
+    at: u64,
+    /// Seconds since the turn began.
+    took_secs: u64,
+}
+
+/// What it takes to roll back a move.
+#[derive(Debug, Clone)]
+struct LastMove {
+    player: PlayerId,
+    points: u32,
+    /// Voice actors this move used for the first time.
+    new_actors: Vec<u32>,
+}
+
+/// Players agreeing to take back the last move.
+#[derive(Debug)]
+struct Undo {
+    votes: Vec<PlayerId>,
+    timeout: Option<AbortHandle>,
+}
+
+impl Drop for Undo {
+    fn drop(&mut self) {
+        if let Some(timeout) = self.timeout.take() {
+            timeout.abort();
+        }
+    }
+}
+
+/// Players who asked to play again after a finished game.
+#[derive(Debug)]
+struct Rematch {
+    votes: Vec<PlayerId>,
+    timeout: Option<AbortHandle>,
+}
+
+impl Drop for Rematch {
+    fn drop(&mut self) {
+        if let Some(timeout) = self.timeout.take() {
+            timeout.abort();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LobbyEntry {
+    /// Players in turn order; the first one is the host.
+    players: Vec<PlayerId>,
+    max_players: usize,
+    game: GameState,
+    config: GameConfig,
+    sessions: HashMap<String, Session>,
+    /// Spectators by reconnect token, with the socket now watching under it.
+    /// `None` while the spectator is disconnected, so the token still gets
+    /// them their seat back.
+    spectators: HashMap<String, Option<Sid>>,
+    rematch: Option<Rematch>,
+    undo: Option<Undo>,
+    /// Players who marked themselves ready for the next game.
+    ready: Vec<PlayerId>,
+    /// Seed rerolls used in the current game.
+    rerolls: u32,
+    created_at: u64,
+    /// Unix seconds of the last join, rejoin, start or move.
+    last_activity: u64,
+}
+
+impl LobbyEntry {
+    /// Aborts the turn timer and any pending evictions before the entry goes away.
+    fn cancel_tasks(&mut self) {
+        self.game.stop_tasks();
+        for eviction in self.sessions.drain().filter_map(|(_, s)| s.eviction) {
+            eviction.abort();
+        }
+        self.rematch = None;
+        self.undo = None;
+    }
+
+    /// Spectators with a socket watching right now.
+    fn watching(&self) -> usize {
+        self.spectators.values().filter(|sid| sid.is_some()).count()
+    }
+
+    /// Whether the sweeper may reap this entry once it has been idle too long.
+    fn is_abandoned(&self) -> bool {
+        let in_progress = self.game.in_progress();
+        !in_progress || !self.players.iter().any(|p| self.is_connected(p))
+    }
+
+    /// Marks the game finished, with the player after `loser` in turn order
+    /// (their opponent, in a two-player game) as the winner.
+    fn finish(&mut self, loser: &PlayerId, reason: EndReason) {
+        let winner = self.next_player(loser);
+        self.end(winner, reason);
+    }
+
+    fn end(&mut self, winner: Option<PlayerId>, reason: EndReason) {
+        self.game.stop_tasks();
+        counter!("games_finished_total").increment(1);
+        self.rerolls = 0;
+        self.undo = None;
+        self.game.phase = Phase::Finished;
+        self.game.deadline = None;
+        self.game.current_turn = None;
+        self.game.winning_team = winner
+            .as_ref()
+            .and_then(|w| self.game.teams.get(w).copied());
+        self.game.winner = winner;
+        self.game.end_reason = Some(reason);
+    }
+
+    /// Ends the game on points. The highest scorer wins, with ties going to
+    /// `last` if given; in team games the team with more points wins, and its
+    /// highest scorer is named the winner. Games with `sudden_death` go to
+    /// overtime on a tie instead, leaving the caller to time the turn.
+    fn end_on_score(&mut self, last: Option<&PlayerId>, reason: EndReason) {
+        if self.config.sudden_death && self.game.overtime.is_none() {
+            let contenders = self.tied_leaders();
+            if contenders.len() > 1 {
+                self.start_overtime(contenders, last);
+                return;
+            }
+        }
+
+        let scores = &self.game.scores;
+        let pick = |players: &[PlayerId]| match last {
+            Some(last) => target_winner(scores, players, last),
+            None => score_winner(scores, players),
+        };
+
+        if self.game.teams.is_empty() {
+            let winner = pick(&self.players);
+            self.end(winner, reason);
+            return;
+        }
+
+        let totals = self.game.team_scores();
+        let total = |team| totals.get(&team).copied().unwrap_or_default();
+        let (red, blue) = (total(Team::Red), total(Team::Blue));
+        let team = if red > blue {
+            Some(Team::Red)
+        } else if blue > red {
+            Some(Team::Blue)
+        } else {
+            last.and_then(|p| self.game.teams.get(p).copied())
+        };
+        let winner = team.and_then(|team| {
+            let members: Vec<_> = self
+                .players
+                .iter()
+                .filter(|p| self.game.teams.get(p) == Some(&team))
+                .cloned()
+                .collect();
+            let last = last.filter(|p| members.contains(p)).or(members.first())?;
+            target_winner(scores, &members, last)
+        });
+        self.end(winner, reason);
+        self.game.winning_team = team;
+    }
+
+    /// Players sharing the top score, in turn order; in team games everyone
+    /// on a team if the team totals are level. Empty when nobody is tied.
+    fn tied_leaders(&self) -> Vec<PlayerId> {
+        let order = self.turn_order();
+
+        if !self.game.teams.is_empty() {
+            let totals = self.game.team_scores();
+            let total = |team| totals.get(&team).copied().unwrap_or_default();
+            if total(Team::Red) != total(Team::Blue) {
+                return Vec::new();
+            }
+            return order
+                .into_iter()
+                .filter(|p| self.game.teams.contains_key(p))
+                .collect();
+        }
+
+        let score = |p: &PlayerId| self.game.scores.get(p).copied().unwrap_or_default();
+        let Some(best) = order.iter().map(score).max() else {
+            return Vec::new();
+        };
+        let leaders: Vec<_> = order.into_iter().filter(|p| score(p) == best).collect();
+        if leaders.len() > 1 {
+            leaders
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Goes to sudden death between `contenders`. A game tied by `last`'s
+    /// move carries on with the next contender; one tied when time ran out
+    /// stays with whoever was moving, if they are in it.
+    fn start_overtime(&mut self, contenders: Vec<PlayerId>, last: Option<&PlayerId>) {
+        self.game.overtime = Some(Overtime {
+            contenders: contenders.clone(),
+            failed: Vec::new(),
+        });
+        let first = match (last, &self.game.current_turn) {
+            (None, Some(current)) if contenders.contains(current) => Some(current.clone()),
+            (Some(after), _) | (None, Some(after)) => self.next_contender(after),
+            (None, None) => None,
+        };
+        // the move that tied the game can't be taken back now
+        self.game.last_move = None;
+        self.undo = None;
+        self.game.deadline = None;
+        self.game
+            .hand_turn(first.or_else(|| contenders.first().cloned()));
+    }
+
+    /// The next contender after `player` still due an overtime turn.
+    fn next_contender(&self, player: &PlayerId) -> Option<PlayerId> {
+        let overtime = self.game.overtime.as_ref()?;
+        let order = self.turn_order();
+        let position = order.iter().position(|p| p == player)?;
+
+        order
+            .iter()
+            .cycle()
+            .skip(position + 1)
+            .take(order.len() - 1)
+            .filter(|p| overtime.contenders.contains(p) && !overtime.failed.contains(p))
+            .find(|p| self.is_connected(p))
+            .cloned()
+    }
+
+    /// Marks `player`'s overtime turn as failed and hands it to the next
+    /// contender. Returns whether that left nobody, drawing the game.
+    fn fail_overtime(&mut self, player: &PlayerId) -> bool {
+        if let Some(overtime) = &mut self.game.overtime {
+            overtime.failed.push(player.clone());
+        }
+
+        match self.next_contender(player) {
+            Some(next) => {
+                self.game.deadline = None;
+                self.game.hand_turn(Some(next));
+                false
+            }
+            None => {
+                self.end(None, EndReason::SuddenDeath);
+                true
+            }
+        }
+    }
+
+    /// Adds a player to the end of the turn order, returning their reconnect token.
+    fn add_player(
+        &mut self,
+        player_id: String,
+        display_name: Option<String>,
+        team: Option<Team>,
+    ) -> String {
+        let token = nanoid!();
+        self.sessions.insert(
+            player_id.clone(),
+            Session {
+                token: token.clone(),
+                display_name: display_name.unwrap_or_else(|| player_id.clone()),
+                eviction: None,
+                team,
+            },
+        );
+        self.players.push(PlayerId(player_id));
+
+        token
+    }
+
+    fn record(&self, game_id: &str) -> GameRecord {
+        GameRecord {
+            game_id: game_id.to_string(),
+            players: self.players.clone(),
+            chain: self.game.chain.clone(),
+            winner: self.game.winner.clone(),
+            end_reason: self.game.end_reason,
+            score: self.game.score,
+            created_at: self.created_at,
+            started_at: self.game.started_at,
+            finished_at: timestamp(),
+            history: self.game.history.clone(),
+            teams: self.game.teams.clone(),
+            winning_team: self.game.winning_team,
+        }
+    }
+
+    fn host(&self) -> &PlayerId {
+        &self.players[0]
+    }
+
+    fn lobby_state(&self) -> LobbyState {
+        LobbyState {
+            host: self.host().clone(),
+            players: self.players.clone(),
+            display_names: self.display_names(),
+        }
+    }
+
+    /// What to call `player`: their chosen name, or else their id.
+    fn display_name(&self, player: &PlayerId) -> String {
+        self.sessions
+            .get(&player.0)
+            .map_or_else(|| player.0.clone(), |s| s.display_name.clone())
+    }
+
+    fn display_names(&self) -> HashMap<PlayerId, String> {
+        self.players
+            .iter()
+            .map(|p| (p.clone(), self.display_name(p)))
+            .collect()
+    }
+
+    fn is_connected(&self, player: &PlayerId) -> bool {
+        self.sessions
+            .get(&player.0)
+            .is_none_or(|session| session.eviction.is_none())
+    }
+
+    /// Players in the order they take turns: join order, or in team games
+    /// alternating between the teams, each in join order.
+    fn turn_order(&self) -> Vec<PlayerId> {
+        if self.game.teams.is_empty() {
+            return self.players.clone();
+        }
+
+        let team = |team| {
+            self.players
+                .iter()
+                .filter(move |p| self.game.teams.get(p) == Some(&team))
+        };
+        let (mut red, mut blue) = (team(Team::Red), team(Team::Blue));
+        let mut order = Vec::with_capacity(self.players.len());
+        loop {
+            match (red.next(), blue.next()) {
+                (None, None) => break,
+                (r, b) => order.extend(r.into_iter().chain(b).cloned()),
+            }
+        }
+        order.extend(
+            self.players
+                .iter()
+                .filter(|p| !self.game.teams.contains_key(p))
+                .cloned(),
+        );
+
+        order
+    }
+
+    /// The connected player whose turn comes after `player`, if any.
+    fn next_player(&self, player: &PlayerId) -> Option<PlayerId> {
+        let order = self.turn_order();
+        let position = order.iter().position(|p| p == player)?;
+
+        order
+            .iter()
+            .cycle()
+            .skip(position + 1)
+            .take(order.len() - 1)
+            .find(|p| self.is_connected(p))
+            .cloned()
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ChainLink {
+    mal_id: u32,
+    title: Option<String>,
+}
+
+/// Read-only recap of a game's chain for `GET /game/{id}/chain`.
+#[derive(Serialize, Debug)]
+struct ChainResponse {
+    chain: Vec<ChainLink>,
+    score: u32,
+    status: &'static str,
+}
+
+/// How much of the game's anime pool is spent, sent as `pool info`.
+#[derive(Serialize, Debug)]
+struct PoolInfo {
+    /// Anime played so far, the seed included.
+    used: usize,
+    /// Voice actors on the latest anime who haven't linked a move yet, a
+    /// rough measure of how many ways the chain can go on. Unknown until
+    /// that anime's cast has been fetched.
+    open_actors: Option<usize>,
+}
+
+impl PoolInfo {
+    fn of(game: &GameState, jikan: &Jikan) -> Self {
+        let open_actors = game
+            .chain
+            .last()
+            .and_then(|&id| jikan.cached_cast(id))
+            .map(|cast| {
+                cast.iter()
+                    .filter(|a| !game.used_actors.contains(a))
+                    .count()
+            });
+
+        PoolInfo {
+            used: game.chain.len(),
+            open_actors,
+        }
+    }
+}
+
+/// Everything needed to draw the board, sent to spectators and rejoining
+/// players as `chain sync`.
+#[derive(Serialize, Debug)]
+struct ChainSync {
+    /// The chain in play order. Anime Jikan couldn't resolve have an empty
+    /// title.
+    chain: Vec<AnimeInfo>,
+    /// Voice actors linking each consecutive pair in `chain`.
+    links: Vec<Vec<u32>>,
+    score: u32,
+    scores: HashMap<PlayerId, u32>,
+    lives: HashMap<PlayerId, u32>,
+    current_turn: Option<PlayerId>,
+    deadline: Option<u64>,
+    /// When the game ends on score, and how long that is from now.
+    ends_at: Option<u64>,
+    game_remaining_secs: Option<u64>,
+    teams: HashMap<PlayerId, Team>,
+    team_scores: HashMap<Team, u32>,
+    pool: PoolInfo,
+    seq: u64,
+}
+
+/// Public listing of a game that still has room for another player.
+#[derive(Serialize, Debug)]
+struct LobbySummary {
+    game_id: String,
+    players: usize,
+    in_progress: bool,
+    created_at: u64,
+}
+
+/// Arguments of a room broadcast, ending with its sequence number. Sent as a
+/// tuple so each one arrives as a separate event argument.
+#[derive(Debug)]
+struct Sequenced(Vec<serde_json::Value>);
+
+impl Sequenced {
+    /// Other arguments under this event's sequence number, for a version of
+    /// it meant for part of the room.
+    fn with_args<T: Serialize>(&self, args: T) -> Sequenced {
+        let mut args = event_args(args);
+        args.extend(self.0.last().cloned());
+
+        Sequenced(args)
+    }
+}
+
+/// Event arguments as separate JSON values, a tuple counting as several.
+fn event_args<T: Serialize>(args: T) -> Vec<serde_json::Value> {
+    match serde_json::to_value(args) {
+        Ok(serde_json::Value::Array(args)) => args,
+        Ok(arg) => vec![arg],
+        Err(e) => {
+            warn!("failed to serialize event arguments: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+impl Serialize for Sequenced {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeTuple;
+
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for arg in &self.0 {
+            tuple.serialize_element(arg)?;
+        }
+        tuple.end()
+    }
+}
+
+/// Who is in a room, broadcast as `room info` whenever that changes.
+#[derive(Serialize, Debug)]
+struct RoomInfo {
+    players: Vec<PlayerId>,
+    display_names: HashMap<PlayerId, String>,
+    spectators: usize,
+}
+
+enum Queued {
+    Duplicate,
+    Waiting,
+    Matched(Sid, Sid),
+}
+
+/// Number of currently connected sockets.
+#[derive(Clone, Default, Debug)]
+struct SocketCount(Arc<AtomicUsize>);
+
+impl SocketCount {
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn decrement(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Everything the handlers share, registered once as state with both
+/// Socket.IO and axum.
+struct AppState {
+    lobby: Lobby,
+    config: Config,
+    anime_info: AnimeInfoCache,
+    top_anime: TopAnimeCache,
+    queue: MatchQueue,
+    sockets: SocketCount,
+    admin_token: AdminToken,
+    metrics: PrometheusHandle,
+}
+
+/// Reply to `GET /stats`.
+#[derive(Serialize, Debug)]
+struct ServerStats {
+    lobbies: usize,
+    games_in_progress: usize,
+    sockets: usize,
+}
+
+/// Sockets waiting to be paired into a random 1v1 game, oldest first.
+#[derive(Clone, Default, Debug)]
+struct MatchQueue(Arc<Mutex<VecDeque<Sid>>>);
+
+impl MatchQueue {
+    /// Queues `sid`, pairing off the two oldest sockets once two are waiting.
+    fn enqueue(&self, sid: Sid) -> Queued {
+        let mut lock = self.0.write_or_recover();
+        if lock.contains(&sid) {
+            return Queued::Duplicate;
+        }
+        lock.push_back(sid);
+
+        match (lock.pop_front(), lock.pop_front()) {
+            (Some(a), Some(b)) => Queued::Matched(a, b),
+            (Some(a), None) => {
+                lock.push_front(a);
+                Queued::Waiting
+            }
+            _ => Queued::Waiting,
+        }
+    }
+
+    fn remove(&self, sid: Sid) {
+        let mut lock = self.0.write_or_recover();
+        lock.retain(|queued| *queued != sid);
+    }
+
+    /// Puts `sid` back at the front, for a socket whose match fell through.
+    fn requeue(&self, sid: Sid) {
+        let mut lock = self.0.write_or_recover();
+        if !lock.contains(&sid) {
+            lock.push_front(sid);
+        }
+    }
+}
+
+/// Debug view of a lobby for `GET /admin/state`; reconnect tokens are left out.
+#[derive(Serialize, Debug)]
+struct LobbySnapshot {
+    players: Vec<PlayerId>,
+    /// Players currently within their reconnect grace period.
+    disconnected: Vec<PlayerId>,
+    max_players: usize,
+    game: GameState,
+    config: GameConfig,
+    spectators: usize,
+    created_at: u64,
+    last_activity: u64,
+}
+
+/// Bearer token guarding the admin endpoints; unset disables them.
+#[derive(Clone)]
+struct AdminToken(Option<Arc<str>>);
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminToken(..)")
+    }
+}
+
+impl AdminToken {
+    /// Whether an `Authorization` header carries this token, compared in
+    /// constant time.
+    fn authorizes(&self, header: Option<&HeaderValue>) -> bool {
+        let Some(token) = &self.0 else {
+            return false;
+        };
+        let Some(given) = header
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+
+        constant_time_eq(given.as_bytes(), token.as_bytes())
+    }
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Clone, Debug)]
+struct Lobby {
+    games: Arc<RwLock<HashMap<String, LobbyEntry>>>,
+    store: Arc<dyn GameStore>,
+    stats: StatsBook,
+    snapshot_interval: Duration,
+}
+
+/// Current roster of a lobby, as sent to its players.
+#[derive(Serialize, Debug, Clone)]
+struct LobbyState {
+    host: PlayerId,
+    /// Players in turn order, host included.
+    players: Vec<PlayerId>,
+    display_names: HashMap<PlayerId, String>,
+}
+
+/// Ack sent to the player whose move was accepted.
+#[derive(Serialize, Debug)]
+struct MoveAccepted<'a> {
+    mal_id: u32,
+    /// Points the move added to the submitter's score.
+    points: u32,
+    connections: &'a [Connection],
+}
+
+/// Ack sent to the player whose pass went through.
+#[derive(Serialize, Debug)]
+struct PassAccepted {
+    /// Whose turn it is now; unset if the pass ended the game.
+    current_turn: Option<PlayerId>,
+    /// Times the passer has passed this game.
+    passes: u32,
+    deadline: Option<u64>,
+}
+
+/// Reply to `whoami`: what the server has on record for the socket.
+#[derive(Serialize, Debug)]
+struct WhoAmI {
+    game_id: String,
+    /// Unset for spectators.
+    player_id: Option<PlayerId>,
+    role: Role,
+    in_progress: bool,
+}
+
+/// Reply to `get_time` describing the turn currently running, if any.
+#[derive(Serialize, Debug)]
+struct TurnTime {
+    /// `lobby`, `in_progress`, `paused` or `finished`.
+    status: &'static str,
+    current_turn: Option<PlayerId>,
+    /// False for games without a turn timer, which never have a deadline.
+    timed: bool,
+    deadline: Option<u64>,
+    remaining_secs: Option<u64>,
+    /// Seconds until the whole game ends, in games with a time limit.
+    game_remaining_secs: Option<u64>,
+}
+
+impl From<&GameState> for TurnTime {
+    fn from(game: &GameState) -> Self {
+        let status = match game.status() {
+            "in_progress" if game.paused_secs.is_some() => "paused",
+            status => status,
+        };
+
+        TurnTime {
+            status,
+            current_turn: game.current_turn.clone(),
+            timed: game.timed,
+            deadline: game.deadline,
+            remaining_secs: game
+                .deadline
+                .map(|d| d.saturating_sub(timestamp()))
+                .or(game.paused_secs),
+            game_remaining_secs: game
+                .ends_at
+                .filter(|_| game.phase != Phase::Finished)
+                .map(|t| t.saturating_sub(timestamp())),
+        }
+    }
+}
+
+/// Broadcast as `turn start` whenever a turn begins or its deadline moves.
+#[derive(Serialize, Debug)]
+struct TurnStart {
+    player_id: PlayerId,
+    /// False when the game has no turn timer.
+    timed: bool,
+    /// Unset while the timer is paused for a reconnect, and always unset in
+    /// untimed games.
+    deadline: Option<u64>,
+    chain_length: usize,
+}
+
+impl TurnStart {
+    /// The turn running in `game`, if one is.
+    fn of(game: &GameState) -> Option<Self> {
+        if game.phase == Phase::Finished {
+            return None;
+        }
+
+        Some(TurnStart {
+            player_id: game.current_turn.clone()?,
+            timed: game.timed,
+            deadline: game.deadline,
+            chain_length: game.chain.len(),
+        })
+    }
+}
+
+/// Sent with a `room_full` rejection. A client that wants to watch instead
+/// follows up with `spectate_game`.
+#[derive(Serialize, Debug)]
+struct FullRoom {
+    players: usize,
+    max_players: usize,
+    can_spectate: bool,
+}
+
+/// The seat a player got by joining.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum PlayerRole {
+    /// First in; picks the rules and can kick.
+    Host,
+    Guest,
+}
+
+/// Acknowledgement sent to a player who successfully joined a lobby.
+#[derive(Serialize, Debug)]
+struct JoinReply {
+    /// Echoed back since guests don't pick their own.
+    player_id: PlayerId,
+    token: String,
+    role: PlayerRole,
+    /// Place in turn order, starting from 0 for the host.
+    position: usize,
+    host_id: PlayerId,
+    lobby: LobbyState,
+    /// The rules the game is played by, with the server's defaults filled in.
+    config: GameConfig,
+}
+
+enum LobbyResult {
+    Joined(Box<JoinReply>),
+    Full(FullRoom),
+    DuplicateId,
+    UnknownGame,
+}
+
+enum RematchResult {
+    /// The first request; the caller should start the acceptance timeout.
+    Requested(Vec<PlayerId>),
+    Waiting(Vec<PlayerId>),
+    /// Every player accepted and the game was reset.
+    Ready(LobbyState),
+    NotFinished,
+    NoGame,
+}
+
+enum PassResult {
+    /// The turn moved on; carries the passer's pass count.
+    Passed(u32, Box<GameState>),
+    /// The pass ended the game: it went over the limit, or was the last
+    /// sudden-death turn.
+    OverLimit(Box<GameState>),
+    NotYourTurn,
+    GameOver,
+    NoGame,
+}
+
+enum ExtendResult {
+    /// Carries the deadline being extended and the extensions left after
+    /// this one.
+    Extended(u64, u32),
+    Exhausted,
+    /// The game has no turn timer to extend.
+    Untimed,
+    /// The game is over or its timer is paused.
+    NoTurn,
+    NotYourTurn,
+}
+
+enum UndoResult {
+    /// An undo was proposed; carries who agreed so far.
+    Requested(Vec<PlayerId>),
+    Waiting(Vec<PlayerId>),
+    /// Everyone agreed and the move was rolled back.
+    Applied(Box<GameState>),
+    NothingToUndo,
+    NoGame,
+}
+
+/// What a player leaving did to their lobby.
+#[derive(Default)]
+struct Removal {
+    /// The final state, if their leaving ended a game in progress.
+    ended: Option<GameState>,
+    /// Whether the lobby went away with them, as it does with the host.
+    closed: bool,
+}
+
+enum TimeoutResult {
+    /// The player has this many lives left and the turn moved on.
+    LifeLost(PlayerId, u32, Box<GameState>),
+    /// The game is over.
+    Eliminated(PlayerId, Box<GameState>),
+    /// A sudden-death turn ran out. No lives are lost; the turn moves on,
+    /// or the game is drawn if it was the last.
+    Overtime(PlayerId, Box<GameState>),
+}
+
+enum SpectateResult {
+    /// Watching under `token`. `resumed` if the token was already a
+    /// spectator's, whose seat this socket took over.
+    Joined {
+        token: String,
+        spectators: usize,
+        resumed: bool,
+        game: Box<GameState>,
+    },
+    Full,
+    NoGame,
+}
+
+enum StartClaim {
+    Claimed,
+    AlreadyStarted,
+    NotReady(&'static str),
+}
+
+enum RerollResult {
+    Allowed,
+    NotHost,
+    NotStarted,
+    /// Someone already played onto the seed.
+    MovesMade,
+    LimitReached,
+    NoGame,
+}
+
+enum MoveResult {
+    Accepted(Box<GameState>),
+    NotYourTurn,
+    Duplicate,
+    GameOver,
+    NoGame,
+}
+
+/// Machine-readable reason an action was rejected.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    RoomFull,
+    SpectatorsFull,
+    UnknownGame,
+    DuplicateId,
+    InvalidPayload,
+    InvalidConfig,
+    InvalidChallenge,
+    InvalidToken,
+    NotYourTurn,
+    DuplicateAnime,
+    UnknownAnime,
+    BlockedAnime,
+    DisallowedType,
+    HintsDisabled,
+    NoSharedVoiceActor,
+    ConnectionReused,
+    GameOver,
+    GameNotOver,
+    SpectatorsCannotPlay,
+    RateLimited,
+    NotInGame,
+    InvalidMessage,
+    NotHost,
+    GameNotStarted,
+    NothingToUndo,
+    ExtendUnavailable,
+    MovesMade,
+    RerollLimit,
+    NotReady,
+    AlreadyStarted,
+    AlreadyInGame,
+    AlreadyQueued,
+    UnknownPlayer,
+    UpstreamError,
+    Timeout,
+}
+
+/// Envelope for every ack and rejection sent to a client, so the frontend
+/// can switch on `status` and `error_code` instead of matching strings.
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response<T> {
+    Ok {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<T>,
+    },
+    Error {
+        error_code: ErrorCode,
+        message: String,
+        /// Context the client can act on, such as where to go instead.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<T>,
+    },
+}
+
+impl<T> Response<T> {
+    fn ok(data: T) -> Self {
+        Response::Ok { data: Some(data) }
+    }
+
+    fn error_with(error_code: ErrorCode, message: impl Into<String>, data: T) -> Self {
+        Response::Error {
+            error_code,
+            message: message.into(),
+            data: Some(data),
+        }
+    }
+}
+
+impl Response<()> {
+    fn done() -> Self {
+        Response::Ok { data: None }
+    }
+
+    fn error(error_code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error {
+            error_code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl Lobby {
+    /// Sets up an empty lobby, rebuilding player stats from the games `store`
+    /// already holds.
+    fn new(store: Arc<dyn GameStore>, snapshot_interval: Duration) -> Self {
+        let stats = StatsBook::default();
+        match store.load() {
+            Ok(records) => records.iter().for_each(|record| stats.record(record)),
+            Err(e) => warn!("failed to load saved games: {}", e),
+        }
+
+        Lobby {
+            games: Arc::default(),
+            store,
+            stats,
+            snapshot_interval,
+        }
+    }
+
+    /// Records a finished game's result and persists it in the background so
+    /// the caller never waits on disk.
+    fn save(&self, record: GameRecord) {
+        self.stats.record(&record);
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = store.save(&record) {
+                warn!("failed to save game {:?}: {}", record.game_id, e);
+            }
+        });
+    }
+
+    /// Sets up an empty lobby under a fresh code, drawing again on the rare
+    /// clash with an existing one. Returns the code.
+    fn reserve(&self, settings: &Config, config: GameConfig) -> String {
+        let mut lock = self.games.write_or_recover();
+        let game_id = std::iter::repeat_with(|| settings.game_code())
+            .find(|code| !lock.contains_key(code))
+            .unwrap();
+        lock.insert(
+            game_id.clone(),
+            LobbyEntry {
+                players: Vec::new(),
+                max_players: config.max_players.unwrap_or(settings.max_players),
+                game: GameState::default(),
+                config,
+                sessions: HashMap::new(),
+                spectators: HashMap::new(),
+                rematch: None,
+                undo: None,
+                ready: Vec::new(),
+                rerolls: 0,
+                created_at: timestamp(),
+                last_activity: timestamp(),
+            },
+        );
+        gauge!("active_lobbies").set(lock.len() as f64);
+        game_id
+    }
+
+    fn insert(
+        &self,
+        game_id: String,
+        player_id: String,
+        display_name: Option<String>,
+        team: Option<Team>,
+    ) -> LobbyResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(&game_id) else {
+            return LobbyResult::UnknownGame;
+        };
+
+        // a finished game gives its id back up for a fresh lobby, but only
+        // once its players have gone and aren't lining up a rematch
+        if entry.game.phase == Phase::Finished
+            && entry.rematch.is_none()
+            && !entry.players.iter().any(|p| entry.is_connected(p))
+        {
+            entry.players.clear();
+            entry.sessions.clear();
+            entry.game = entry.game.fresh();
+            entry.rematch = None;
+            entry.ready.clear();
+        }
+
+        if entry.players.iter().any(|p| p.0 == player_id) {
+            return LobbyResult::DuplicateId;
+        }
+
+        if entry.players.len() >= entry.max_players {
+            return LobbyResult::Full(FullRoom {
+                players: entry.players.len(),
+                max_players: entry.max_players,
+                can_spectate: true,
+            });
+        }
+
+        let token = entry.add_player(player_id.clone(), display_name, team);
+        entry.last_activity = timestamp();
+        let position = entry.players.len() - 1;
+        let reply = JoinReply {
+            player_id: PlayerId(player_id),
+            token,
+            role: if position == 0 {
+                PlayerRole::Host
+            } else {
+                PlayerRole::Guest
+            },
+            position,
+            host_id: entry.host().clone(),
+            lobby: entry.lobby_state(),
+            config: entry.config.clone(),
+        };
+
+        if reply.role == PlayerRole::Host {
+            return LobbyResult::Joined(Box::new(reply));
+        }
+
+        // a game left with nobody to move resumes with the newcomer
+        if !entry.game.chain.is_empty() && entry.game.current_turn.is_none() {
+            let newcomer = entry.players.last().cloned();
+            entry.game.hand_turn(newcomer);
+        }
+
+        LobbyResult::Joined(Box::new(reply))
+    }
+
+    /// Frees a player's slot, closing the whole lobby if they were the host.
+    fn remove(&self, game_id: String, player_id: String) -> Removal {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(&game_id) else {
+            return Removal::default();
+        };
+
+        if let Some(eviction) = entry
+            .sessions
+            .remove(&player_id)
+            .and_then(|session| session.eviction)
+        {
+            eviction.abort();
+        }
+
+        let Some(position) = entry.players.iter().position(|p| p.0 == player_id) else {
+            info!(
+                "invalid removal of player. game ID: {:?}, player ID: {:?}",
+                game_id, player_id
+            );
+            return Removal::default();
+        };
+
+        let leaver = entry.players[position].clone();
+        let in_progress = entry.game.in_progress();
+        let ended = (in_progress && (position == 0 || entry.players.len() <= 2)).then(|| {
+            entry.finish(&leaver, EndReason::PlayerLeft);
+            self.save(entry.record(&game_id));
+            entry.game.clone()
+        });
+
+        if position == 0 {
+            info!(
+                "host left. game ID: {:?}, player ID: {:?}",
+                game_id, player_id
+            );
+            entry.cancel_tasks();
+            lock.remove(&game_id);
+            gauge!("active_lobbies").set(lock.len() as f64);
+            return Removal {
+                ended,
+                closed: true,
+            };
+        }
+
+        info!(
+            "player left. game ID: {:?}, player ID: {:?}",
+            game_id, player_id
+        );
+
+        // the turn passes on before the leaver's place in the order is lost
+        if entry
+            .game
+            .current_turn
+            .as_ref()
+            .is_some_and(|p| p.0 == player_id)
+        {
+            let next = entry.next_player(&leaver);
+            entry.game.hand_turn(next);
+        }
+        entry.players.remove(position);
+        entry.ready.retain(|p| p != &leaver);
+        entry.game.teams.remove(&leaver);
+
+        Removal {
+            ended,
+            closed: false,
+        }
+    }
+
+    /// Reclaims the slot matching `token`, cancelling any pending eviction.
+    fn rejoin(&self, game_id: &str, token: &str) -> Option<PlayerId> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        let (player_id, session) = entry
+            .sessions
+            .iter_mut()
+            .find(|(_, session)| session.token == token)?;
+
+        if let Some(eviction) = session.eviction.take() {
+            eviction.abort();
+        }
+        let player_id = PlayerId(player_id.clone());
+        entry.last_activity = timestamp();
+
+        Some(player_id)
+    }
+
+    /// Gives a disconnected player `grace` to rejoin before their slot is
+    /// freed.
+    fn schedule_eviction(
+        &self,
+        io: SocketIo,
+        game_id: String,
+        player_id: PlayerId,
+        grace: Duration,
+    ) {
+        let task = tokio::spawn({
+            let lobby = self.clone();
+            let game_id = game_id.clone();
+            let player_id = player_id.clone();
+            async move {
+                tokio::time::sleep(grace).await;
+                if !lobby.take_eviction(&game_id, &player_id) {
+                    return;
+                }
+
+                info!(
+                    "reconnect grace expired. game ID: {:?}, player ID: {:?}",
+                    game_id, player_id
+                );
+                let removal = lobby.remove(game_id.clone(), player_id.0.clone());
+                io.within(game_id.clone())
+                    .emit("opponent left", &lobby.sequenced(&game_id, (&player_id,)))
+                    .ok();
+                emit_room_info(&io, &lobby, &game_id);
+
+                match removal.ended {
+                    Some(game) => emit_game_over(&io, &lobby, game_id.clone(), &game),
+                    None if !removal.closed => {
+                        resume_turn(&io, &lobby, game_id.clone());
+                    }
+                    None => {}
+                }
+                if removal.closed {
+                    close_lobby(&io, &game_id);
+                }
+            }
+        });
+
+        let mut lock = self.games.write_or_recover();
+        match lock
+            .get_mut(&game_id)
+            .and_then(|entry| entry.sessions.get_mut(&player_id.0))
+        {
+            Some(session) => {
+                if let Some(old) = session.eviction.replace(task.abort_handle()) {
+                    old.abort();
+                }
+            }
+            None => task.abort(),
+        }
+    }
+
+    /// Clears a pending eviction, returning whether there was one; a player
+    /// who rejoined in time has none left.
+    fn take_eviction(&self, game_id: &str, player_id: &PlayerId) -> bool {
+        let mut lock = self.games.write_or_recover();
+        lock.get_mut(game_id)
+            .and_then(|entry| entry.sessions.get_mut(&player_id.0))
+            .and_then(|session| session.eviction.take())
+            .is_some()
+    }
+
+    /// Attaches socket `sid` as a spectator. A known `token` hands that
+    /// spectator's seat to `sid`; otherwise a new seat is issued unless the
+    /// game already has `max` (0 for no limit).
+    fn add_spectator(
+        &self,
+        game_id: &str,
+        token: Option<&str>,
+        sid: Sid,
+        max: usize,
+    ) -> SpectateResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return SpectateResult::NoGame;
+        };
+
+        let known = token.filter(|t| entry.spectators.contains_key(*t));
+        let (token, resumed) = match known {
+            Some(token) => (token.to_string(), true),
+            None if max != 0 && entry.watching() >= max => {
+                return SpectateResult::Full;
+            }
+            None => (nanoid!(), false),
+        };
+        entry.spectators.insert(token.clone(), Some(sid));
+
+        SpectateResult::Joined {
+            token,
+            spectators: entry.watching(),
+            resumed,
+            game: Box::new(entry.game.clone()),
+        }
+    }
+
+    /// Takes away the seat of the spectator watching under `token`,
+    /// returning the new spectator count. Does nothing if another socket has
+    /// since resumed the seat.
+    fn remove_spectator(&self, game_id: &str, token: &str, sid: Sid) -> Option<usize> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        if entry.spectators.get(token) != Some(&Some(sid)) {
+            return None;
+        }
+        entry.spectators.remove(token);
+
+        Some(entry.watching())
+    }
+
+    /// Marks the spectator watching under `token` as disconnected, keeping
+    /// their seat for a rejoin. Returns the new spectator count. Does nothing
+    /// if another socket has since resumed the seat, so a late disconnect
+    /// can't drop them.
+    fn detach_spectator(&self, game_id: &str, token: &str, sid: Sid) -> Option<usize> {
+        let mut lock = self.games.write_or_recover();
+        let seat = lock.get_mut(game_id)?.spectators.get_mut(token)?;
+
+        if *seat != Some(sid) {
+            return None;
+        }
+        *seat = None;
+
+        Some(lock.get(game_id)?.watching())
+    }
+
+    /// Whether `token` seats a spectator of `game_id` rather than a player.
+    fn is_spectator_token(&self, game_id: &str, token: &str) -> bool {
+        self.games
+            .read_or_recover()
+            .get(game_id)
+            .is_some_and(|entry| entry.spectators.contains_key(token))
+    }
+
+    /// Drops abandoned entries that have been idle for longer than `ttl`,
+    /// returning how many were removed.
+    fn sweep(&self, ttl: Duration) -> usize {
+        let mut lock = self.games.write_or_recover();
+        let cutoff = timestamp().saturating_sub(ttl.as_secs());
+        let before = lock.len();
+
+        lock.retain(|_, entry| {
+            let keep = entry.last_activity >= cutoff || !entry.is_abandoned();
+            if !keep {
+                entry.cancel_tasks();
+            }
+            keep
+        });
+
+        gauge!("active_lobbies").set(lock.len() as f64);
+        before - lock.len()
+    }
+
+    /// Full copy of every lobby, for debugging.
+    fn snapshot(&self) -> HashMap<String, LobbySnapshot> {
+        let lock = self.games.read_or_recover();
+        lock.iter()
+            .map(|(game_id, entry)| {
+                let snapshot = LobbySnapshot {
+                    players: entry.players.clone(),
+                    disconnected: entry
+                        .players
+                        .iter()
+                        .filter(|p| !entry.is_connected(p))
+                        .cloned()
+                        .collect(),
+                    max_players: entry.max_players,
+                    game: entry.game.clone(),
+                    config: entry.config.clone(),
+                    spectators: entry.watching(),
+                    created_at: entry.created_at,
+                    last_activity: entry.last_activity,
+                };
+                (game_id.clone(), snapshot)
+            })
+            .collect()
+    }
+
+    /// How many lobbies exist and how many of them have a game underway.
+    fn counts(&self) -> (usize, usize) {
+        let lock = self.games.read_or_recover();
+        let in_progress = lock
+            .values()
+            .filter(|entry| entry.game.in_progress())
+            .count();
+        (lock.len(), in_progress)
+    }
+
+    /// Snapshot of the games that are neither full nor finished.
+    fn joinable(&self) -> Vec<LobbySummary> {
+        let lock = self.games.read_or_recover();
+        lock.iter()
+            .filter(|(_, entry)| {
+                entry.game.phase != Phase::Finished && entry.players.len() < entry.max_players
+            })
+            .map(|(game_id, entry)| LobbySummary {
+                game_id: game_id.clone(),
+                players: entry.players.len(),
+                in_progress: !entry.game.chain.is_empty(),
+                created_at: entry.created_at,
+            })
+            .collect()
+    }
+
+    fn set_config(&self, game_id: &str, config: GameConfig) {
+        let mut lock = self.games.write_or_recover();
+        if let Some(entry) = lock.get_mut(game_id) {
+            if let Some(max_players) = config.max_players {
+                entry.max_players = max_players;
+            } else if config.teams {
+                entry.max_players = TEAM_SIZE * 2;
+            }
+            entry.config = config;
+        }
+    }
+
+    /// The game's turn length, or `default` if it didn't pick one.
+    fn turn_secs(&self, game_id: &str, default: u64) -> u64 {
+        let lock = self.games.read_or_recover();
+        lock.get(game_id)
+            .and_then(|entry| entry.config.turn_secs)
+            .unwrap_or(default)
+    }
+
+    /// Whether spectators of `game_id` should be kept from the turn timer.
+    fn hides_timer(&self, game_id: &str) -> bool {
+        self.games
+            .read_or_recover()
+            .get(game_id)
+            .is_some_and(|entry| entry.config.hide_spectator_timer)
+    }
+
+    /// Stamps a room broadcast with the game's next sequence number. `args`
+    /// must be a tuple of the event's arguments; the number goes after them.
+    fn sequenced<T: Serialize>(&self, game_id: &str, args: T) -> Sequenced {
+        let seq = {
+            let mut lock = self.games.write_or_recover();
+            lock.get_mut(game_id).map_or(0, |entry| {
+                entry.game.seq += 1;
+                entry.game.seq
+            })
+        };
+
+        let mut args = event_args(args);
+        args.push(seq.into());
+
+        Sequenced(args)
+    }
+
+    /// What `player` goes by in the game, if they are in it.
+    fn display_name(&self, game_id: &str, player: &PlayerId) -> Option<String> {
+        let lock = self.games.read_or_recover();
+        lock.get(game_id)
+            .filter(|entry| entry.players.contains(player))
+            .map(|entry| entry.display_name(player))
+    }
+
+    fn room_info(&self, game_id: &str) -> Option<RoomInfo> {
+        let lock = self.games.read_or_recover();
+        lock.get(game_id).map(|entry| RoomInfo {
+            players: entry.players.clone(),
+            display_names: entry.display_names(),
+            spectators: entry.watching(),
+        })
+    }
+
+    fn lobby_state(&self, game_id: &str) -> Option<LobbyState> {
+        let lock = self.games.read_or_recover();
+        lock.get(game_id)
+            .filter(|entry| !entry.players.is_empty())
+            .map(LobbyEntry::lobby_state)
+    }
+
+    fn config(&self, game_id: &str) -> Option<GameConfig> {
+        let lock = self.games.read_or_recover();
+        lock.get(game_id).map(|entry| entry.config.clone())
+    }
+
+    fn game_state(&self, game_id: &str) -> Option<GameState> {
+        let lock = self.games.read_or_recover();
+        lock.get(game_id).map(|entry| entry.game.clone())
+    }
+
+    /// Records `player`'s vote for a rematch. Once every player has voted
+    /// while connected, the game is reset so it can be seeded again.
+    fn request_rematch(&self, game_id: &str, player: &PlayerId) -> RematchResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return RematchResult::NoGame;
+        };
+
+        if entry.game.phase != Phase::Finished || !entry.players.contains(player) {
+            return RematchResult::NotFinished;
+        }
+
+        let first = entry.rematch.is_none();
+        let rematch = entry.rematch.get_or_insert_with(|| Rematch {
+            votes: Vec::new(),
+            timeout: None,
+        });
+        if !rematch.votes.contains(player) {
+            rematch.votes.push(player.clone());
+        }
+
+        let votes = rematch.votes.clone();
+        let ready = entry.players.len() >= 2
+            && entry
+                .players
+                .iter()
+                .all(|p| votes.contains(p) && entry.is_connected(p));
+
+        if ready {
+            entry.rematch = None;
+            entry.game = entry.game.fresh();
+            // claimed for the rematch's seed, like `claim_start`
+            entry.game.phase = Phase::InProgress;
+            entry.last_activity = timestamp();
+            return RematchResult::Ready(entry.lobby_state());
+        }
+
+        if first {
+            RematchResult::Requested(votes)
+        } else {
+            RematchResult::Waiting(votes)
+        }
+    }
+
+    /// Records the task that declines a pending rematch once it times out.
+    fn set_rematch_timeout(&self, game_id: &str, timeout: AbortHandle) {
+        let mut lock = self.games.write_or_recover();
+        match lock.get_mut(game_id).and_then(|e| e.rematch.as_mut()) {
+            Some(rematch) => {
+                if let Some(old) = rematch.timeout.replace(timeout) {
+                    old.abort();
+                }
+            }
+            None => timeout.abort(),
+        }
+    }
+
+    /// Drops a pending rematch, returning whether there was one.
+    fn decline_rematch(&self, game_id: &str) -> bool {
+        let mut lock = self.games.write_or_recover();
+        lock.get_mut(game_id)
+            .and_then(|entry| entry.rematch.take())
+            .map(|mut rematch| release_task(&mut rematch.timeout))
+            .is_some()
+    }
+
+    /// Forgets a disconnected player's rematch vote; they have to ask again
+    /// once they are back.
+    fn withdraw_rematch(&self, game_id: &str, player: &PlayerId) {
+        let mut lock = self.games.write_or_recover();
+        if let Some(rematch) = lock.get_mut(game_id).and_then(|e| e.rematch.as_mut()) {
+            rematch.votes.retain(|p| p != player);
+        }
+    }
+
+    /// Adds `player`'s agreement to undoing the last move, rolling it back
+    /// once every player agrees. The turn goes back to whoever made it.
+    fn request_undo(&self, game_id: &str, player: &PlayerId) -> UndoResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return UndoResult::NoGame;
+        };
+
+        if entry.game.phase == Phase::Finished
+            || entry.game.last_move.is_none()
+            || !entry.players.contains(player)
+        {
+            return UndoResult::NothingToUndo;
+        }
+
+        let first = entry.undo.is_none();
+        let undo = entry.undo.get_or_insert_with(|| Undo {
+            votes: Vec::new(),
+            timeout: None,
+        });
+        if !undo.votes.contains(player) {
+            undo.votes.push(player.clone());
+        }
+
+        let votes = undo.votes.clone();
+        if !entry.players.iter().all(|p| votes.contains(p)) {
+            return if first {
+                UndoResult::Requested(votes)
+            } else {
+                UndoResult::Waiting(votes)
+            };
+        }
+
+        entry.undo = None;
+        let Some(last) = entry.game.last_move.take() else {
+            return UndoResult::NothingToUndo;
+        };
+        let undone = entry.game.chain.pop();
+        entry.game.links.pop();
+        entry
+            .game
+            .used_actors
+            .retain(|a| !last.new_actors.contains(a));
+        entry.game.score -= last.points;
+        if let Some(score) = entry.game.scores.get_mut(&last.player) {
+            *score -= last.points;
+        }
+        entry.game.log(
+            &last.player,
+            TurnAction::Undo,
+            undone,
+            -i64::from(last.points),
+        );
+        entry.game.hand_turn(Some(last.player));
+        entry.last_activity = timestamp();
+
+        UndoResult::Applied(Box::new(entry.game.clone()))
+    }
+
+    /// Records the task that rejects a pending undo once it times out.
+    fn set_undo_timeout(&self, game_id: &str, timeout: AbortHandle) {
+        let mut lock = self.games.write_or_recover();
+        match lock.get_mut(game_id).and_then(|e| e.undo.as_mut()) {
+            Some(undo) => {
+                if let Some(old) = undo.timeout.replace(timeout) {
+                    old.abort();
+                }
+            }
+            None => timeout.abort(),
+        }
+    }
+
+    /// Drops a pending undo, returning whether there was one. `timed_out`
+    /// is set when called from the timeout task itself.
+    fn reject_undo(&self, game_id: &str, timed_out: bool) -> bool {
+        let mut lock = self.games.write_or_recover();
+        lock.get_mut(game_id)
+            .and_then(|entry| entry.undo.take())
+            .map(|mut undo| {
+                if timed_out {
+                    release_task(&mut undo.timeout);
+                }
+            })
+            .is_some()
+    }
+
+    /// Marks `player` ready or not, returning who is ready now.
+    fn set_ready(&self, game_id: &str, player: &PlayerId, ready: bool) -> Option<Vec<PlayerId>> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        entry.ready.retain(|p| p != player);
+        if ready {
+            entry.ready.push(player.clone());
+        }
+        entry.last_activity = timestamp();
+
+        Some(entry.ready.clone())
+    }
+
+    /// Moves the game out of the lobby if it can start, so that only one
+    /// `start game` ever gets to pick a seed.
+    fn claim_start(&self, game_id: &str) -> StartClaim {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return StartClaim::NotReady("unknown game");
+        };
+
+        if entry.game.phase != Phase::Lobby {
+            return StartClaim::AlreadyStarted;
+        }
+        if entry.players.len() < 2 {
+            return StartClaim::NotReady("waiting for more players");
+        }
+        if !entry.players.iter().all(|p| entry.ready.contains(p)) {
+            return StartClaim::NotReady("not every player is ready");
+        }
+
+        entry.game.phase = Phase::InProgress;
+        StartClaim::Claimed
+    }
+
+    /// Puts a claimed game back in the lobby after it failed to start.
+    fn release_start(&self, game_id: &str) {
+        let mut lock = self.games.write_or_recover();
+        if let Some(entry) = lock.get_mut(game_id) {
+            if entry.game.phase == Phase::InProgress && entry.game.chain.is_empty() {
+                entry.game.phase = Phase::Lobby;
+            }
+        }
+    }
+
+    /// Uses up one of the host's seed rerolls, if they have one left and
+    /// nobody has moved yet.
+    fn take_reroll(&self, game_id: &str, player: &PlayerId, max_rerolls: u32) -> RerollResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return RerollResult::NoGame;
+        };
+
+        if entry.players.first() != Some(player) {
+            return RerollResult::NotHost;
+        }
+        if !entry.game.in_progress() {
+            return RerollResult::NotStarted;
+        }
+        if entry.game.chain.len() > 1 {
+            return RerollResult::MovesMade;
+        }
+        if entry.rerolls >= max_rerolls {
+            return RerollResult::LimitReached;
+        }
+
+        entry.rerolls += 1;
+        RerollResult::Allowed
+    }
+
+    /// Resets the game to a fresh chain starting at `mal_id`, returning who
+    /// moves first.
+    fn start(
+        &self,
+        game_id: &str,
+        mal_id: u32,
+        first_turn: FirstTurn,
+        turn_secs: u64,
+        lives: u32,
+    ) -> Option<PlayerId> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        entry.game.stop_tasks();
+        let first = first_turn.choose(&entry.players)?.clone();
+        entry.last_activity = timestamp();
+        entry.ready.clear();
+        entry.game = GameState {
+            chain: vec![mal_id],
+            phase: Phase::InProgress,
+            current_turn: Some(first.clone()),
+            started_at: Some(timestamp()),
+            turn_started_at: Some(timestamp()),
+            timed: turn_secs > 0,
+            turn_secs,
+            lives: entry.players.iter().map(|p| (p.clone(), lives)).collect(),
+            teams: if entry.config.teams {
+                assign_teams(&entry.players, |p| entry.sessions.get(&p.0)?.team)
+            } else {
+                HashMap::new()
+            },
+            ..entry.game.fresh()
+        };
+
+        Some(first)
+    }
+
+    /// Appends an accepted anime to the chain, credits `player` with `points`
+    /// for the link through `actors`, and hands the turn over.
+    fn push_anime(
+        &self,
+        game_id: &str,
+        player: &PlayerId,
+        mal_id: u32,
+        points: u32,
+        actors: &[u32],
+    ) -> MoveResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return MoveResult::NoGame;
+        };
+
+        if entry.game.phase == Phase::Finished {
+            return MoveResult::GameOver;
+        }
+
+        if entry.game.current_turn.as_ref() != Some(player) {
+            return MoveResult::NotYourTurn;
+        }
+
+        if entry.game.has_played(mal_id) {
+            return MoveResult::Duplicate;
+        }
+
+        entry.game.chain.push(mal_id);
+        entry.game.links.push(actors.to_vec());
+        let mut new_actors = Vec::new();
+        for actor in actors {
+            if !entry.game.used_actors.contains(actor) {
+                entry.game.used_actors.push(*actor);
+                new_actors.push(*actor);
+            }
+        }
+        entry.game.last_move = Some(LastMove {
+            player: player.clone(),
+            points,
+            new_actors,
+        });
+        entry.undo = None;
+        entry.game.score += points;
+        *entry.game.scores.entry(player.clone()).or_default() += points;
+        entry
+            .game
+            .log(player, TurnAction::Move, Some(mal_id), i64::from(points));
+        entry.last_activity = timestamp();
+        let target = entry.config.target_chain_length;
+        if entry.game.overtime.is_some() {
+            entry.end(Some(player.clone()), EndReason::SuddenDeath);
+            self.save(entry.record(game_id));
+        } else if target.is_some_and(|len| entry.game.chain.len() >= len as usize) {
+            entry.end_on_score(Some(player), EndReason::TargetReached);
+            if entry.game.phase == Phase::Finished {
+                self.save(entry.record(game_id));
+            }
+        } else if let Some(next) = entry.next_player(player) {
+            entry.game.hand_turn(Some(next));
+        }
+
+        MoveResult::Accepted(Box::new(entry.game.clone()))
+    }
+
+    /// Records `player` passing their turn, docking `penalty` points. Going
+    /// over `max_passes` (if non-zero) forfeits the game.
+    fn pass(&self, game_id: &str, player: &PlayerId, max_passes: u32, penalty: u32) -> PassResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return PassResult::NoGame;
+        };
+
+        if entry.game.phase == Phase::Finished {
+            return PassResult::GameOver;
+        }
+
+        if entry.game.current_turn.as_ref() != Some(player) {
+            return PassResult::NotYourTurn;
+        }
+
+        entry.last_activity = timestamp();
+        entry.game.last_move = None;
+        entry.undo = None;
+
+        // passing up a sudden-death turn costs nothing but the turn
+        if entry.game.overtime.is_some() {
+            let passes = entry.game.passes.get(player).copied().unwrap_or_default();
+            entry.game.log(player, TurnAction::Pass, None, 0);
+            if entry.fail_overtime(player) {
+                self.save(entry.record(game_id));
+                return PassResult::OverLimit(Box::new(entry.game.clone()));
+            }
+            return PassResult::Passed(passes, Box::new(entry.game.clone()));
+        }
+
+        let passes = entry.game.passes.entry(player.clone()).or_default();
+        *passes += 1;
+        let passes = *passes;
+
+        if max_passes > 0 && passes > max_passes {
+            entry.game.log(player, TurnAction::Pass, None, 0);
+            entry.finish(player, EndReason::PassLimit);
+            self.save(entry.record(game_id));
+            return PassResult::OverLimit(Box::new(entry.game.clone()));
+        }
+
+        let score = entry.game.scores.entry(player.clone()).or_default();
+        let docked = penalty.min(*score);
+        *score -= docked;
+        entry.game.score -= docked;
+        entry
+            .game
+            .log(player, TurnAction::Pass, None, -i64::from(docked));
+
+        if let Some(next) = entry.next_player(player) {
+            entry.game.hand_turn(Some(next));
+        }
+
+        PassResult::Passed(passes, Box::new(entry.game.clone()))
+    }
+
+    /// Uses up one of `player`'s extensions on their turn, falling back to
+    /// `default_max` if the game didn't set its own limit.
+    fn take_extend(&self, game_id: &str, player: &PlayerId, default_max: u32) -> ExtendResult {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return ExtendResult::NoTurn;
+        };
+
+        if !entry.game.timed && entry.game.phase != Phase::Finished {
+            return ExtendResult::Untimed;
+        }
+
+        // finished games have no deadline
+        let Some(deadline) = entry.game.deadline else {
+            return ExtendResult::NoTurn;
+        };
+
+        if entry.game.current_turn.as_ref() != Some(player) {
+            return ExtendResult::NotYourTurn;
+        }
+
+        let max = entry.config.max_extends.unwrap_or(default_max);
+        if entry.game.extends_used >= max {
+            return ExtendResult::Exhausted;
+        }
+
+        entry.game.extends_used += 1;
+        entry.last_activity = timestamp();
+        ExtendResult::Extended(deadline, max - entry.game.extends_used)
+    }
+
+    /// Stops the turn timer while a player is disconnected, remembering how
+    /// much time was left.
+    fn pause_timer(&self, game_id: &str) {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            return;
+        };
+
+        let (Some(timer), Some(deadline)) = (entry.game.timer.take(), entry.game.deadline.take())
+        else {
+            return;
+        };
+
+        timer.abort();
+        entry.game.paused_secs = Some(deadline.saturating_sub(timestamp()));
+    }
+
+    /// Takes the time left on a paused turn once every player is connected again.
+    fn take_paused(&self, game_id: &str) -> Option<u64> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        if entry.game.phase == Phase::Finished
+            || !entry.players.iter().all(|p| entry.is_connected(p))
+        {
+            return None;
+        }
+
+        entry.game.paused_secs.take()
+    }
+
+    /// Records the task sending the current turn's snapshots, stopping the
+    /// previous turn's.
+    fn set_snapshots(&self, game_id: &str, snapshots: AbortHandle) {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock
+            .get_mut(game_id)
+            .filter(|e| e.game.phase != Phase::Finished)
+        else {
+            snapshots.abort();
+            return;
+        };
+
+        if let Some(old) = entry.game.snapshots.replace(snapshots) {
+            old.abort();
+        }
+    }
+
+    /// Records the task that ends the game at `ends_at`.
+    fn set_clock(&self, game_id: &str, ends_at: u64, clock: AbortHandle) {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock
+            .get_mut(game_id)
+            .filter(|e| e.game.phase != Phase::Finished)
+        else {
+            clock.abort();
+            return;
+        };
+
+        if let Some(old) = entry.game.clock.replace(clock) {
+            old.abort();
+        }
+        entry.game.ends_at = Some(ends_at);
+    }
+
+    /// Ends the game on score if it is still the one due to end at `ends_at`.
+    /// The state comes back unfinished if the game went to sudden death.
+    fn end_on_time_limit(&self, game_id: &str, ends_at: u64) -> Option<GameState> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        if entry.game.phase == Phase::Finished || entry.game.ends_at != Some(ends_at) {
+            return None;
+        }
+
+        release_task(&mut entry.game.clock);
+        entry.end_on_score(None, EndReason::TimeLimit);
+        if entry.game.phase == Phase::Finished {
+            self.save(entry.record(game_id));
+        }
+
+        Some(entry.game.clone())
+    }
+
+    /// Records the running turn timer, cancelling the one it replaces.
+    fn set_timer(&self, game_id: &str, deadline: u64, timer: AbortHandle) {
+        let mut lock = self.games.write_or_recover();
+        let Some(entry) = lock.get_mut(game_id) else {
+            timer.abort();
+            return;
+        };
+
+        if let Some(old) = entry.game.timer.replace(timer) {
+            old.abort();
+        }
+        entry.game.deadline = Some(deadline);
+        entry.game.paused_secs = None;
+    }
+
+    /// Takes a life from the player whose turn expires at `deadline`, if it
+    /// is still the current turn. The turn moves on while they have lives
+    /// left; losing the last one ends the game.
+    fn time_out_turn(&self, game_id: &str, deadline: u64) -> Option<TimeoutResult> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        if entry.game.phase == Phase::Finished || entry.game.deadline != Some(deadline) {
+            return None;
+        }
+
+        release_task(&mut entry.game.timer);
+        let timed_out = entry.game.current_turn.clone()?;
+        entry.game.log(&timed_out, TurnAction::Timeout, None, 0);
+        entry.game.last_move = None;
+        entry.undo = None;
+
+        if entry.game.overtime.is_some() {
+            if entry.fail_overtime(&timed_out) {
+                self.save(entry.record(game_id));
+            }
+            return Some(TimeoutResult::Overtime(
+                timed_out,
+                Box::new(entry.game.clone()),
+            ));
+        }
+        let lives = entry.game.lives.entry(timed_out.clone()).or_insert(1);
+        *lives = lives.saturating_sub(1);
+        let lives = *lives;
+
+        match entry.next_player(&timed_out).filter(|_| lives > 0) {
+            Some(next) => {
+                entry.game.deadline = None;
+                entry.game.hand_turn(Some(next));
+                Some(TimeoutResult::LifeLost(
+                    timed_out,
+                    lives,
+                    Box::new(entry.game.clone()),
+                ))
+            }
+            None => {
+                entry.finish(&timed_out, EndReason::Timeout);
+                self.save(entry.record(game_id));
+                Some(TimeoutResult::Eliminated(
+                    timed_out,
+                    Box::new(entry.game.clone()),
+                ))
+            }
+        }
+    }
+
+    /// Ends the game with `loser` conceding. Returns `None` unless it was in
+    /// progress.
+    fn forfeit(&self, game_id: &str, loser: &PlayerId) -> Option<GameState> {
+        let mut lock = self.games.write_or_recover();
+        let entry = lock.get_mut(game_id)?;
+
+        if !entry.game.in_progress() {
+            return None;
+        }
+
+        entry.finish(loser, EndReason::Forfeit);
+        self.save(entry.record(game_id));
+
+        Some(entry.game.clone())
+    }
+}
+
+/// Winner once the target chain length is reached: the highest scorer, with
+/// ties going to `last`, the player who placed the final link, and otherwise
+/// to whoever is earliest in turn order.
+fn target_winner(
+    scores: &HashMap<PlayerId, u32>,
+    players: &[PlayerId],
+    last: &PlayerId,
+) -> Option<PlayerId> {
+    let score = |p: &PlayerId| scores.get(p).copied().unwrap_or_default();
+    let best = players.iter().map(score).max()?;
+
+    if score(last) == best {
+        return Some(last.clone());
+    }
+    players.iter().find(|p| score(p) == best).cloned()
+}
+
+/// The single highest scorer, or `None` on a tie.
+fn score_winner(scores: &HashMap<PlayerId, u32>, players: &[PlayerId]) -> Option<PlayerId> {
+    let score = |p: &PlayerId| scores.get(p).copied().unwrap_or_default();
+    let best = players.iter().map(score).max()?;
+
+    match players
+        .iter()
+        .filter(|p| score(p) == best)
+        .collect::<Vec<_>>()[..]
+    {
+        [winner] => Some(winner.clone()),
+        _ => None,
+    }
+}
+
+/// Starts the clock on a game limited to `secs` seconds, returning when it
+/// runs out. Zero means no limit and no clock.
+fn start_game_clock(io: &SocketIo, lobby: &Lobby, game_id: &str, secs: u64) -> Option<u64> {
+    if secs == 0 {
+        return None;
+    }
+
+    let ends_at = timestamp() + secs;
+    let task = tokio::spawn({
+        let io = io.clone();
+        let lobby = lobby.clone();
+        let game_id = game_id.to_string();
+        async move {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            let Some(game) = lobby.end_on_time_limit(&game_id, ends_at) else {
+                return;
+            };
+            info!("game time limit reached. game ID: {:?}", game_id);
+            if game.phase == Phase::Finished {
+                emit_game_over(&io, &lobby, game_id, &game);
+            } else {
+                let deadline = start_turn_timer(&io, &lobby, &game_id, SUDDEN_DEATH_SECS);
+                emit_sudden_death(&io, &lobby, &game_id, &game, deadline);
+                emit_turn_start(&io, &lobby, &game_id);
+            }
+        }
+    });
+    lobby.set_clock(game_id, ends_at, task.abort_handle());
+
+    Some(ends_at)
+}
+
+/// Starts the timer on a fresh `secs`-second turn, returning its deadline.
+/// Games with a turn length of zero are untimed and get no timer.
+fn start_turn_timer(io: &SocketIo, lobby: &Lobby, game_id: &str, secs: u64) -> Option<u64> {
+    (secs > 0).then(|| {
+        schedule_turn_timeout(
+            io.clone(),
+            lobby.clone(),
+            game_id.to_string(),
+            timestamp() + secs,
+        )
+    })
+}
+
+/// Starts (or restarts) the turn timer for a game so it fires at `deadline`.
+fn schedule_turn_timeout(io: SocketIo, lobby: Lobby, game_id: String, deadline: u64) -> u64 {
+    let task = tokio::spawn({
+        let lobby = lobby.clone();
+        let game_id = game_id.clone();
+        async move {
+            let delay = deadline.saturating_sub(timestamp());
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+            on_turn_timeout(io, lobby, game_id, deadline);
+        }
+    });
+
+    lobby.set_timer(&game_id, deadline, task.abort_handle());
+
+    deadline
+}
+
+fn on_turn_timeout(io: SocketIo, lobby: Lobby, game_id: String, deadline: u64) {
+    let (timed_out, game) = match lobby.time_out_turn(&game_id, deadline) {
+        None => return,
+        Some(TimeoutResult::LifeLost(timed_out, lives, game)) => {
+            info!(
+                "turn timed out. game ID: {:?}, player ID: {:?}, lives left: {}",
+                game_id, timed_out, lives
+            );
+            io.within(game_id.clone())
+                .emit("life lost", &lobby.sequenced(&game_id, (&timed_out, lives)))
+                .ok();
+            start_turn_timer(&io, &lobby, &game_id, game.turn_secs);
+            emit_turn_start(&io, &lobby, &game_id);
+            return;
+        }
+        Some(TimeoutResult::Eliminated(timed_out, game)) => (timed_out, game),
+        Some(TimeoutResult::Overtime(timed_out, game)) if game.phase != Phase::Finished => {
+            info!(
+                "sudden-death turn timed out. game ID: {:?}, player ID: {:?}",
+                game_id, timed_out
+            );
+            io.within(game_id.clone())
+                .emit(
+                    "turn timeout",
+                    &lobby.sequenced(&game_id, (timed_out, deadline)),
+                )
+                .ok();
+            start_turn_timer(&io, &lobby, &game_id, SUDDEN_DEATH_SECS);
+            emit_turn_start(&io, &lobby, &game_id);
+            return;
+        }
+        Some(TimeoutResult::Overtime(timed_out, game)) => {
+            info!("sudden death drawn. game ID: {:?}", game_id);
+            io.within(game_id.clone())
+                .emit(
+                    "turn timeout",
+                    &lobby.sequenced(&game_id, (timed_out, deadline)),
+                )
+                .ok();
+            emit_game_over(&io, &lobby, game_id, &game);
+            return;
+        }
+    };
+
+    info!(
+        "turn timed out. game ID: {:?}, player ID: {:?}",
+        game_id, timed_out
+    );
+
+    io.within(game_id.clone())
+        .emit("life lost", &lobby.sequenced(&game_id, (&timed_out, 0)))
+        .ok();
+    io.within(game_id.clone())
+        .emit(
+            "turn timeout",
+            &lobby.sequenced(&game_id, (timed_out, deadline)),
+        )
+        .ok();
+    emit_game_over(&io, &lobby, game_id, &game);
+}
+
+/// Declines a pending rematch if the other players have not all accepted
+/// within `REMATCH_TIMEOUT`.
+fn schedule_rematch_timeout(io: SocketIo, lobby: Lobby, game_id: String) {
+    let task = tokio::spawn({
+        let lobby = lobby.clone();
+        let game_id = game_id.clone();
+        async move {
+            tokio::time::sleep(REMATCH_TIMEOUT).await;
+            if lobby.decline_rematch(&game_id) {
+                info!("rematch declined. game ID: {:?}", game_id);
+                io.within(game_id.clone())
+                    .emit("rematch declined", &lobby.sequenced(&game_id, ()))
+                    .ok();
+            }
+        }
+    });
+
+    lobby.set_rematch_timeout(&game_id, task.abort_handle());
+}
+
+/// Rejects a pending undo if the other players have not all agreed within
+/// `UNDO_TIMEOUT`.
+fn schedule_undo_timeout(io: SocketIo, lobby: Lobby, game_id: String) {
+    let task = tokio::spawn({
+        let lobby = lobby.clone();
+        let game_id = game_id.clone();
+        async move {
+            tokio::time::sleep(UNDO_TIMEOUT).await;
+            if lobby.reject_undo(&game_id, true) {
+                info!("undo timed out. game ID: {:?}", game_id);
+                io.within(game_id.clone())
+                    .emit("undo rejected", &lobby.sequenced(&game_id, ()))
+                    .ok();
+            }
+        }
+    });
+
+    lobby.set_undo_timeout(&game_id, task.abort_handle());
+}
+
+/// Broadcasts the final result to everyone in the room, including the
+/// socket that ended the game.
+fn emit_game_over(io: &SocketIo, lobby: &Lobby, game_id: String, game: &GameState) {
+    info!(
+        "game over. game ID: {:?}, winner: {:?}, score: {}, reason: {:?}",
+        game_id, game.winner, game.score, game.end_reason
+    );
+    let args = (
+        &game.winner,
+        &game.chain,
+        game.score,
+        game.end_reason,
+        &game.history,
+        game.winning_team,
+    );
+    io.within(game_id.clone())
+        .emit("game over", &lobby.sequenced(&game_id, args))
+        .ok();
+}
+
+/// Announces sudden death: who is in it, whose turn it is and until when.
+fn emit_sudden_death(
+    io: &SocketIo,
+    lobby: &Lobby,
+    game_id: &str,
+    game: &GameState,
+    deadline: Option<u64>,
+) {
+    let Some(overtime) = &game.overtime else {
+        return;
+    };
+
+    info!("sudden death. game ID: {:?}", game_id);
+    let contenders = &overtime.contenders;
+    emit_timer_event(
+        io,
+        None,
+        lobby,
+        game_id,
+        "sudden death",
+        (contenders, &game.current_turn, deadline),
+        (contenders, &game.current_turn, None::<u64>),
+    );
+}
+
+/// Sends the room each team's points, in team games only.
+fn emit_team_score(io: &SocketIo, lobby: &Lobby, game_id: &str, game: &GameState) {
+    if game.teams.is_empty() {
+        return;
+    }
+
+    io.within(game_id.to_string())
+        .emit(
+            "team score",
+            &lobby.sequenced(game_id, (game.team_scores(),)),
+        )
+        .ok();
+}
+
+/// Tells everyone in the room whose turn it is and until when.
+fn emit_turn_start(io: &SocketIo, lobby: &Lobby, game_id: &str) {
+    let Some(game) = lobby.game_state(game_id) else {
+        return;
+    };
+    let (Some(turn), Some(hidden)) = (TurnStart::of(&game), TurnStart::of(&game.without_timer()))
+    else {
+        return;
+    };
+
+    emit_timer_event(io, None, lobby, game_id, "turn start", (turn,), (hidden,));
+    schedule_snapshots(io, lobby, game_id);
+}
+
+/// Re-announces the turn every `snapshot_interval` until the next one
+/// starts, so clients that missed its `turn start` can catch up.
+fn schedule_snapshots(io: &SocketIo, lobby: &Lobby, game_id: &str) {
+    let interval = lobby.snapshot_interval;
+    if interval.is_zero() {
+        return;
+    }
+
+    let task = tokio::spawn({
+        let io = io.clone();
+        let lobby = lobby.clone();
+        let game_id = game_id.to_string();
+        async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(game) = lobby.game_state(&game_id) else {
+                    return;
+                };
+                let (Some(turn), Some(hidden)) =
+                    (TurnStart::of(&game), TurnStart::of(&game.without_timer()))
+                else {
+                    return;
+                };
+                emit_timer_event(
+                    &io,
+                    None,
+                    &lobby,
+                    &game_id,
+                    "state snapshot",
+                    (turn,),
+                    (hidden,),
+                );
+            }
+        }
+    });
+    lobby.set_snapshots(game_id, task.abort_handle());
+}
+
+/// Tells everyone in the room how much of the anime pool is spent.
+fn emit_pool_info(io: &SocketIo, lobby: &Lobby, jikan: &Jikan, game_id: &str) {
+    let Some(game) = lobby.game_state(game_id) else {
+        return;
+    };
+
+    let pool = PoolInfo::of(&game, jikan);
+    io.within(game_id.to_owned())
+        .emit("pool info", &lobby.sequenced(game_id, (pool,)))
+        .ok();
+}
+
+/// Resolves the chain behind `game` and sends it to `s` as a `chain sync`.
+/// Titles come from the per-id cache, so only anime nobody has looked up
+/// recently cost a Jikan call.
+fn emit_chain_sync(s: &SocketRef, anime_info: &AnimeInfoCache, game: GameState) {
+    let s = s.clone();
+    let anime_info = anime_info.clone();
+    tokio::spawn(async move {
+        let chain = futures::future::join_all(game.chain.iter().map(|&mal_id| {
+            let anime_info = anime_info.clone();
+            async move {
+                anime_info.get(mal_id).await.unwrap_or(AnimeInfo {
+                    mal_id,
+                    title: String::new(),
+                    title_english: None,
+                    image_url: None,
+                    anime_type: None,
+                })
+            }
+        }))
+        .await;
+
+        let sync = ChainSync {
+            pool: PoolInfo::of(&game, &anime_info.jikan),
+            team_scores: game.team_scores(),
+            chain,
+            links: game.links,
+            score: game.score,
+            scores: game.scores,
+            lives: game.lives,
+            teams: game.teams,
+            game_remaining_secs: game.ends_at.map(|t| t.saturating_sub(timestamp())),
+            ends_at: game.ends_at,
+            current_turn: game.current_turn,
+            deadline: game.deadline,
+            seq: game.seq,
+        };
+        s.emit("chain sync", &sync).ok();
+    });
+}
+
+/// Sends the room's current roster and audience size to everyone in it. Call
+/// after any change to who is in the room.
+fn emit_room_info(io: &SocketIo, lobby: &Lobby, game_id: &str) {
+    if let Some(room) = lobby.room_info(game_id) {
+        io.within(game_id.to_owned())
+            .emit("room info", &lobby.sequenced(game_id, (room,)))
+            .ok();
+    }
+}
+
+/// Frees a player's slot and lets the rest of the room know, ending the game
+/// if too few players remain to continue it.
+fn remove_player(io: &SocketIo, lobby: &Lobby, game_id: String, player_id: PlayerId) {
+    let removal = lobby.remove(game_id.clone(), player_id.0.clone());
+
+    io.within(game_id.clone())
+        .emit("player left", &lobby.sequenced(&game_id, (&player_id,)))
+        .ok();
+    emit_room_info(io, lobby, &game_id);
+
+    if let Some(game) = removal.ended {
+        emit_game_over(io, lobby, game_id.clone(), &game);
+    }
+    if removal.closed {
+        close_lobby(io, &game_id);
+    }
+}
+
+/// Tells everyone left in a lobby that has gone away, and takes them out of
+/// it so they can join or start another game.
+fn close_lobby(io: &SocketIo, game_id: &str) {
+    info!("lobby closed. game ID: {:?}", game_id);
+    for socket in io.within(game_id.to_owned()).sockets().unwrap_or_default() {
+        socket.extensions.remove::<GameId>();
+        socket.extensions.remove::<PlayerId>();
+        socket.extensions.remove::<Role>();
+        socket.extensions.remove::<SpectatorToken>();
+        let _ = socket.leave([game_id.to_owned(), spectator_room(game_id)]);
+        socket.emit("lobby closed", &game_id).ok();
+    }
+}
+
+/// Seats `s` as a spectator of `game_id`, resuming the seat behind `token`
+/// if it has one, and sends it the board.
+fn spectate(
+    s: &SocketRef,
+    io: &SocketIo,
+    app: &AppState,
+    game_id: String,
+    token: Option<String>,
+    ack: AckSender,
+) {
+    let (token, spectators, resumed, game) =
+        match app
+            .lobby
+            .add_spectator(&game_id, token.as_deref(), s.id, app.config.max_spectators)
+        {
+            SpectateResult::Joined {
+                token,
+                spectators,
+                resumed,
+                game,
+            } => (token, spectators, resumed, *game),
+            SpectateResult::Full => {
+                ack.send(&Response::error(
+                    ErrorCode::SpectatorsFull,
+                    "this game has as many spectators as it allows",
+                ))
+                .ok();
+                return;
+            }
+            SpectateResult::NoGame => {
+                ack.send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+                    .ok();
+                return;
+            }
+        };
+
+    info!(
+        "spectator joined. game ID: {:?}, resumed: {}",
+        game_id, resumed
+    );
+    s.extensions.insert(GameId(game_id.clone()));
+    s.extensions.insert(Role::Spectator);
+    s.extensions.insert(SpectatorToken(token.clone()));
+    ack.send(&Response::ok(SpectateReply { token })).ok();
+
+    let _ = s.join([game_id.clone(), spectator_room(&game_id)]);
+    let game = if app.lobby.hides_timer(&game_id) {
+        game.without_timer()
+    } else {
+        game
+    };
+    s.emit("game state", &game).ok();
+    s.emit("score", &game.scores).ok();
+    if let Some(turn) = TurnStart::of(&game) {
+        s.emit("turn start", &turn).ok();
+    }
+    emit_chain_sync(s, &app.anime_info, game);
+    s.within(game_id.clone())
+        .emit("spectators", &app.lobby.sequenced(&game_id, (spectators,)))
+        .ok();
+    emit_room_info(io, &app.lobby, &game_id);
+}
+
+/// Restarts a paused turn timer with the time it had left, if nobody else is
+/// still away. Returns the new deadline.
+fn resume_turn(io: &SocketIo, lobby: &Lobby, game_id: String) -> Option<u64> {
+    let remaining = lobby.take_paused(&game_id)?;
+
+    let deadline = schedule_turn_timeout(
+        io.clone(),
+        lobby.clone(),
+        game_id.clone(),
+        timestamp() + remaining,
+    );
+    emit_turn_start(io, lobby, &game_id);
+
+    Some(deadline)
+}
+
+/// Whether `event` from this socket exceeds `rate` per second. Rejected
+/// events are logged; the caller acks the rejection.
+fn is_rate_limited(s: &SocketRef, event: &'static str, rate: u32) -> bool {
+    let limited = s
+        .extensions
+        .get::<RateLimiter>()
+        .is_some_and(|limiter| !limiter.allow(event, rate));
+    if limited {
+        info!("rate limited {:?} from socket {:?}", event, s.id);
+    }
+    limited
+}
+
+/// The room a game's spectators join on top of the game's own, so events
+/// can leave them out.
+fn spectator_room(game_id: &str) -> String {
+    format!("{}/spectators", game_id)
+}
+
+/// Broadcasts an event that gives away the turn timer to the room (or, with
+/// `from`, to everyone else in it). If the game hides the timer from
+/// spectators they get `hidden` instead, under the same sequence number so
+/// their stream has no gaps.
+fn emit_timer_event<T: Serialize, U: Serialize>(
+    io: &SocketIo,
+    from: Option<&SocketRef>,
+    lobby: &Lobby,
+    game_id: &str,
+    event: &'static str,
+    args: T,
+    hidden: U,
+) {
+    let room = |room: String| match from {
+        Some(s) => s.to(room),
+        None => io.within(room),
+    };
+    let full = lobby.sequenced(game_id, args);
+
+    if !lobby.hides_timer(game_id) {
+        room(game_id.to_owned()).emit(event, &full).ok();
+        return;
+    }
+
+    room(game_id.to_owned())
+        .except(spectator_room(game_id))
+        .emit(event, &full)
+        .ok();
+    room(spectator_room(game_id))
+        .emit(event, &full.with_args(hidden))
+        .ok();
+}
+
+fn is_spectator(s: &SocketRef) -> bool {
+    s.extensions.get::<Role>() == Some(Role::Spectator)
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Like `timestamp`, in unix milliseconds.
+fn timestamp_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[derive(Debug)]
+enum JikanError {
+    RateLimited,
+    Status(StatusCode),
+    Timeout,
+    Request(reqwest::Error),
+}
+
+impl JikanError {
+    fn is_not_found(&self) -> bool {
+        matches!(self, JikanError::Status(StatusCode::NOT_FOUND))
+    }
+
+    fn code(&self) -> ErrorCode {
+        match self {
+            JikanError::Timeout => ErrorCode::Timeout,
+            _ => ErrorCode::UpstreamError,
+        }
+    }
+
+    fn reason(&self) -> FailureReason {
+        match self {
+            JikanError::RateLimited => FailureReason::RateLimited,
+            JikanError::Timeout => FailureReason::Timeout,
+            _ => FailureReason::UpstreamError,
+        }
+    }
+}
+
+/// Why a move couldn't be judged, as opposed to being against the rules.
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum FailureReason {
+    UpstreamError,
+    Timeout,
+    RateLimited,
+}
+
+/// Payload of `move failed`, sent to the submitter when Jikan couldn't be
+/// asked about their move.
+#[derive(Serialize, Debug)]
+struct MoveFailed {
+    mal_id: u32,
+    reason: FailureReason,
+    message: String,
+}
+
+impl fmt::Display for JikanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JikanError::RateLimited => write!(f, "rate limited by Jikan"),
+            JikanError::Status(status) => write!(f, "Jikan responded with {}", status),
+            JikanError::Timeout => write!(f, "Jikan did not respond in time"),
+            JikanError::Request(e) => write!(f, "Jikan request failed: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for JikanError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            JikanError::Timeout
+        } else {
+            JikanError::Request(e)
+        }
+    }
+}
+
+/// Responses from one Jikan endpoint, keyed by the id in its path. Holds at
+/// most its capacity, dropping the least recently used first, and forgets
+/// entries `ttl` after they were fetched.
+#[derive(Debug)]
+struct ResponseCache<K: Hash + Eq, V> {
+    entries: Mutex<LruCache<K, (Instant, V)>>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Hash + Eq, V: Clone> ResponseCache<K, V> {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        ResponseCache {
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN),
+            )),
+            ttl,
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+        }
+    }
+
+    /// The cached response for `key`, counting a hit or a miss.
+    fn get(&self, key: &K) -> Option<V> {
+        let value = self.peek(key);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        value
+    }
+
+    /// Like `get`, without counting towards the hit rate.
+    fn peek(&self, key: &K) -> Option<V> {
+        let mut lock = self.entries.write_or_recover();
+        match lock.get(key) {
+            Some((at, value)) if at.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                lock.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.entries
+            .write_or_recover()
+            .put(key, (Instant::now(), value));
+    }
+
+    /// Hits and misses since the last call.
+    fn take_stats(&self) -> (u64, u64) {
+        (
+            self.hits.swap(0, Ordering::Relaxed),
+            self.misses.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+/// Client for the Jikan API rooted at a configurable base URL.
+#[derive(Clone, Debug)]
+struct Jikan {
+    base_url: Arc<str>,
+    /// Shared so every request reuses the same connection pool.
+    client: reqwest::Client,
+    /// Calls in a row that couldn't reach Jikan or got a server error back.
+    failures: Arc<AtomicU32>,
+    /// Japanese voice actors per anime, from `/anime/{id}/characters`.
+    casts: Arc<ResponseCache<u32, HashMap<u32, Credit>>>,
+    /// Voice roles per person, one entry per role, from
+    /// `/people/{id}/voices`.
+    roles: Arc<ResponseCache<u32, Vec<VoicedAnime>>>,
+}
+
+impl Jikan {
+    /// A client caching up to `cache_size` responses per endpoint for
+    /// `cache_ttl` each.
+    fn new(
+        base_url: &str,
+        client: reqwest::Client,
+        cache_size: usize,
+        cache_ttl: Duration,
+    ) -> Self {
+        Jikan {
+            base_url: Arc::from(base_url.trim_end_matches('/')),
+            client,
+            failures: Arc::default(),
+            casts: Arc::new(ResponseCache::new(cache_size, cache_ttl)),
+            roles: Arc::new(ResponseCache::new(cache_size, cache_ttl)),
+        }
+    }
+
+    /// The voice actor ids on `mal_id` if a previous lookup fetched them.
+    fn cached_cast(&self, mal_id: u32) -> Option<Vec<u32>> {
+        self.casts
+            .peek(&mal_id)
+            .map(|cast| cast.keys().copied().collect())
+    }
+
+    /// Whether recent calls suggest Jikan is reachable. Judged from calls
+    /// already made so probes never hit the API themselves.
+    fn is_reachable(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) < JIKAN_UNREADY_AFTER
+    }
+
+    /// GETs a Jikan endpoint, keeping track of whether it is reachable.
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, JikanError> {
+        let res = self.get_with_retries(path).await;
+
+        // a 404 for an unknown anime still means Jikan is up
+        let unreachable = match &res {
+            Ok(_) => false,
+            Err(JikanError::Status(status)) => status.is_server_error(),
+            Err(_) => true,
+        };
+        if unreachable {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failures.store(0, Ordering::Relaxed);
+        }
+
+        res
+    }
+
+    /// GETs a Jikan endpoint, retrying with exponential backoff while rate
+    /// limited. A `Retry-After` header takes precedence over the backoff.
+    async fn get_with_retries<T: DeserializeOwned>(&self, path: &str) -> Result<T, JikanError> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut backoff = Duration::from_secs(1);
+
+        for attempt in 0..=JIKAN_MAX_RETRIES {
+            let started = Instant::now();
+            let res = self.client.get(&url).send().await;
+            histogram!("jikan_request_duration_seconds").record(started.elapsed().as_secs_f64());
+            let res = res?;
+            let status = res.status();
+
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                if attempt == JIKAN_MAX_RETRIES {
+                    break;
+                }
+
+                let delay = res
+                    .headers()
+                    .get(http::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+                    .map_or(backoff, Duration::from_secs);
+                warn!("rate limited by Jikan; retrying {} in {:?}", url, delay);
+                tokio::time::sleep(delay).await;
+                backoff *= 2;
+                continue;
+            }
+
+            if !status.is_success() {
+                return Err(JikanError::Status(status));
+            }
+
+            return Ok(res.json::<T>().await?);
+        }
+
+        Err(JikanError::RateLimited)
+    }
+
+    /// The Japanese voice actors credited on an anime, keyed by MAL id.
+    async fn japanese_voice_actors(&self, mal_id: u32) -> Result<HashMap<u32, Credit>, JikanError> {
+        if let Some(cast) = self.casts.get(&mal_id) {
+            return Ok(cast);
+        }
+
+        let path = format!("/anime/{}/characters", mal_id);
+        let json = self.get::<CharactersResponse>(&path).await?;
+
+        let mut credits = HashMap::<u32, Credit>::new();
+        for role in json.data {
+            for va in role.voice_actors {
+                if va.language != "Japanese" {
+                    continue;
+                }
+                credits
+                    .entry(va.person.mal_id)
+                    .or_insert_with(|| Credit {
+                        name: va.person.name,
+                        characters: Vec::new(),
+                    })
+                    .characters
+                    .push(role.character.clone());
+            }
+        }
+        self.casts.insert(mal_id, credits.clone());
+
+        Ok(credits)
+    }
+
+    /// The Japanese voice actors two anime have in common.
+    ///
+    /// Both casts are fetched at once. If either lookup fails the move can't
+    /// be judged, so the whole call fails rather than comparing against a
+    /// partial cast.
+    async fn shared_voice_actors(&self, a: u32, b: u32) -> Result<Vec<Connection>, JikanError> {
+        let (a_actors, b_actors) =
+            tokio::try_join!(self.japanese_voice_actors(a), self.japanese_voice_actors(b))?;
+
+        Ok(connections(a_actors, b_actors))
+    }
+
+    /// The anime behind each of a person's voice roles.
+    async fn voice_roles(&self, person_id: u32) -> Result<Vec<VoicedAnime>, JikanError> {
+        if let Some(roles) = self.roles.get(&person_id) {
+            return Ok(roles);
+        }
+
+        let path = format!("/people/{}/voices", person_id);
+        let json = self.get::<PersonVoicesResponse>(&path).await?;
+        let roles: Vec<VoicedAnime> = json.data.into_iter().map(|role| role.anime).collect();
+        self.roles.insert(person_id, roles.clone());
+
+        Ok(roles)
+    }
+
+    /// How many voice roles a person is credited with.
+    async fn voice_role_count(&self, person_id: u32) -> Option<usize> {
+        self.voice_roles(person_id)
+            .await
+            .ok()
+            .map(|roles| roles.len())
+    }
+}
+
+/// Pairs up the voice actors credited on both anime, ordered by name.
+fn connections(from: HashMap<u32, Credit>, mut to: HashMap<u32, Credit>) -> Vec<Connection> {
+    let mut connections: Vec<Connection> = from
+        .into_iter()
+        .filter_map(|(mal_id, credit)| {
+            let other = to.remove(&mal_id)?;
+            Some(Connection {
+                mal_id,
+                name: credit.name,
+                from_characters: credit.characters,
+                to_characters: other.characters,
+            })
+        })
+        .collect();
+    connections.sort_by(|a, b| a.name.cmp(&b.name));
+
+    connections
+}
+
+/// Points for a connection through a voice actor with `roles` credited
+/// roles; the less prolific the actor, the more the link is worth.
+fn rarity_points(roles: usize) -> u32 {
+    match roles {
+        0..=10 => 5,
+        11..=50 => 4,
+        51..=150 => 3,
+        151..=400 => 2,
+        _ => 1,
+    }
+}
+
+/// Points for a move, scored by the rarest of the connecting actors. Actors
+/// whose roles could not be fetched count as the most common.
+async fn connection_points(jikan: &Jikan, connections: &[Connection]) -> u32 {
+    let counts =
+        futures::future::join_all(connections.iter().map(|c| jikan.voice_role_count(c.mal_id)))
+            .await;
+
+    counts
+        .into_iter()
+        .map(|roles| roles.map_or(1, rarity_points))
+        .max()
+        .unwrap_or(1)
+}
+
+async fn start_game(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>, ack: AckSender) {
+    let span = event_span(&s, "start game");
+    async move {
+        if is_spectator(&s) {
+            ack.send(&Response::error(
+                ErrorCode::SpectatorsCannotPlay,
+                "spectators cannot play",
+            ))
+            .ok();
+            return;
+        }
+
+        if is_rate_limited(&s, "start game", app.config.start_game_rate) {
+            ack.send(&Response::error(ErrorCode::RateLimited, "rate limited"))
+                .ok();
+            return;
+        }
+
+        info!("game id {:?}", s.extensions.get::<GameId>());
+        let Some(x) = s.extensions.get::<GameId>() else {
+            return;
+        };
+
+        match app.lobby.claim_start(&x.0) {
+            StartClaim::Claimed => {}
+            StartClaim::AlreadyStarted => {
+                info!("not starting game {:?}: already started", x);
+                let res = Response::error(ErrorCode::AlreadyStarted, "game already started");
+                s.emit("already started", &res).ok();
+                ack.send(&res).ok();
+                return;
+            }
+            StartClaim::NotReady(reason) => {
+                info!("not starting game {:?}: {}", x, reason);
+                let res = Response::error(ErrorCode::NotReady, reason);
+                s.emit("not ready", &res).ok();
+                ack.send(&res).ok();
+                return;
+            }
+        }
+
+        let started = seed_game(
+            &io,
+            &app.lobby,
+            &app.config,
+            &app.top_anime,
+            &app.anime_info,
+            x.0.clone(),
+            None,
+        )
+        .await;
+        if !started {
+            app.lobby.release_start(&x.0);
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Lets the host swap the seed anime for another before anyone has played.
+async fn on_reroll_seed(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>, ack: AckSender) {
+    let span = event_span(&s, "reroll_seed");
+    async move {
+        let (Some(g), Some(p)) = (s.extensions.get::<GameId>(), s.extensions.get::<PlayerId>())
+        else {
+            ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+                .ok();
+            return;
+        };
+
+        let res = match app.lobby.take_reroll(&g.0, &p, app.config.max_rerolls) {
+            RerollResult::Allowed => Response::done(),
+            RerollResult::NotHost => {
+                Response::error(ErrorCode::NotHost, "only the host can reroll")
+            }
+            RerollResult::NotStarted => {
+                Response::error(ErrorCode::GameNotStarted, "no game in progress")
+            }
+            RerollResult::MovesMade => {
+                Response::error(ErrorCode::MovesMade, "the chain already has moves")
+            }
+            RerollResult::LimitReached => {
+                Response::error(ErrorCode::RerollLimit, "no rerolls left this game")
+            }
+            RerollResult::NoGame => Response::error(ErrorCode::UnknownGame, "unknown game"),
+        };
+        let allowed = matches!(res, Response::Ok { .. });
+        ack.send(&res).ok();
+        if !allowed {
+            return;
+        }
+
+        info!("rerolling seed. game ID: {:?}, player ID: {:?}", g, p);
+        let seed = app
+            .lobby
+            .game_state(&g.0)
+            .and_then(|game| game.chain.first().copied());
+        seed_game(
+            &io,
+            &app.lobby,
+            &app.config,
+            &app.top_anime,
+            &app.anime_info,
+            g.0,
+            seed,
+        )
+        .await;
+    }
+    .instrument(span)
+    .await
+}
+
+/// Picks a seed anime other than `avoid` from the game's pool and starts the
+/// first turn. Returns whether the game started.
+async fn seed_game(
+    io: &SocketIo,
+    lobby: &Lobby,
+    config: &Config,
+    top_anime: &TopAnimeCache,
+    anime_info: &AnimeInfoCache,
+    game_id: String,
+    avoid: Option<u32>,
+) -> bool {
+    let Some(game_config) = lobby.config(&game_id) else {
+        return false;
+    };
+
+    // a challenge's seed stands unless it is rerolled away or since blocked
+    let seed = game_config
+        .seed_anime
+        .filter(|&id| Some(id) != avoid && !config.is_blocked(&game_config, id));
+    let choosen_anime = if let Some(seed) = seed {
+        seed
+    } else {
+        let ids = match top_anime.get(&game_config.seed_path()).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                warn!("failed to fetch top anime: {}", e);
+                let res = Response::error(e.code(), e.to_string());
+                io.within(game_id.clone())
+                    .emit("start failed", &lobby.sequenced(&game_id, (res,)))
+                    .ok();
+                return false;
+            }
+        };
+
+        let allowed: Vec<u32> = ids
+            .iter()
+            .copied()
+            .filter(|&id| !config.is_blocked(&game_config, id))
+            .collect();
+        if allowed.is_empty() {
+            warn!("every seed candidate is blocked. game ID: {:?}", game_id);
+            let res = Response::error(
+                ErrorCode::BlockedAnime,
+                "every anime in the pool is blocked",
+            );
+            io.within(game_id.clone())
+                .emit("start failed", &lobby.sequenced(&game_id, (res,)))
+                .ok();
+            return false;
+        }
+
+        let others: Vec<u32> = allowed
+            .iter()
+            .copied()
+            .filter(|&id| Some(id) != avoid)
+            .collect();
+        let pool = if others.is_empty() { &allowed } else { &others };
+
+        let Some(choosen_anime) = choose_anime(pool, &mut config.seed_source.rng()) else {
+            return false;
+        };
+        choosen_anime
+    };
+
+    let info = match anime_info.get(choosen_anime).await {
+        Ok(info) => info,
+        Err(e) => {
+            warn!("failed to fetch anime {}: {}", choosen_anime, e);
+            let res = Response::error(e.code(), e.to_string());
+            io.within(game_id.clone())
+                .emit("start failed", &lobby.sequenced(&game_id, (res,)))
+                .ok();
+            return false;
+        }
+    };
+
+    info!(
+        "starting game; anime: {:?}, ts: {}",
+        choosen_anime,
+        timestamp()
+    );
+    let turn_secs = game_config.turn_secs.unwrap_or(config.turn_secs);
+    let lives = game_config.lives.unwrap_or(config.lives);
+    let Some(first) = lobby.start(&game_id, choosen_anime, config.first_turn, turn_secs, lives)
+    else {
+        return false;
+    };
+    counter!("games_started_total").increment(1);
+    let deadline = start_turn_timer(io, lobby, &game_id, turn_secs);
+    let game_secs = game_config.max_game_secs.unwrap_or(config.max_game_secs);
+    let ends_at = start_game_clock(io, lobby, &game_id, game_secs);
+    let ts = timestamp();
+    emit_timer_event(
+        io,
+        None,
+        lobby,
+        &game_id,
+        "start game",
+        (&info, ts, deadline, &first),
+        (&info, ts, None::<u64>, &first),
+    );
+    if let Some(ends_at) = ends_at {
+        io.within(game_id.clone())
+            .emit("game clock", &lobby.sequenced(&game_id, (ends_at,)))
+            .ok();
+    }
+    if let Some(game) = lobby.game_state(&game_id).filter(|g| !g.teams.is_empty()) {
+        io.within(game_id.clone())
+            .emit("teams", &lobby.sequenced(&game_id, (&game.teams,)))
+            .ok();
+    }
+    emit_pool_info(io, lobby, &anime_info.jikan, &game_id);
+    emit_turn_start(io, lobby, &game_id);
+
+    true
+}
+
+async fn on_request_rematch(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>, ack: AckSender) {
+    let span = event_span(&s, "request_rematch");
+    async move {
+        if is_spectator(&s) {
+            ack.send(&Response::error(
+                ErrorCode::SpectatorsCannotPlay,
+                "spectators cannot play",
+            ))
+            .ok();
+            return;
+        }
+
+        let Some(x) = s.extensions.get::<GameId>() else {
+            return;
+        };
+
+        let Some(p) = s.extensions.get::<PlayerId>() else {
+            return;
+        };
+
+        match app.lobby.request_rematch(&x.0, &p) {
+            RematchResult::Requested(votes) => {
+                info!("rematch requested. game ID: {:?}, player ID: {:?}", x, p);
+                schedule_rematch_timeout(io, app.lobby.clone(), x.0.clone());
+                ack.send(&Response::ok(&votes)).ok();
+                s.to(x.0.clone())
+                    .emit("rematch requested", &app.lobby.sequenced(&x.0, (&p,)))
+                    .ok();
+            }
+            RematchResult::Waiting(votes) => {
+                ack.send(&Response::ok(&votes)).ok();
+                s.to(x.0.clone())
+                    .emit("rematch requested", &app.lobby.sequenced(&x.0, (&p,)))
+                    .ok();
+            }
+            RematchResult::Ready(lobby) => {
+                info!("rematch starting. game ID: {:?}", x);
+                ack.send(&Response::done()).ok();
+                s.within(x.0.clone())
+                    .emit("rematch start", &app.lobby.sequenced(&x.0, (&lobby,)))
+                    .ok();
+                let started = seed_game(
+                    &io,
+                    &app.lobby,
+                    &app.config,
+                    &app.top_anime,
+                    &app.anime_info,
+                    x.0.clone(),
+                    None,
+                )
+                .await;
+                if !started {
+                    app.lobby.release_start(&x.0);
+                }
+            }
+            RematchResult::NotFinished => {
+                ack.send(&Response::error(
+                    ErrorCode::GameNotOver,
+                    "game is not over yet",
+                ))
+                .ok();
+            }
+            RematchResult::NoGame => {
+                ack.send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+                    .ok();
+            }
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Proposes, or agrees to, taking back the last move.
+fn on_request_undo(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "request_undo").entered();
+    if is_spectator(&s) {
+        ack.send(&Response::error(
+            ErrorCode::SpectatorsCannotPlay,
+            "spectators cannot play",
+        ))
+        .ok();
+        return;
+    }
+
+    let (Some(x), Some(p)) = (s.extensions.get::<GameId>(), s.extensions.get::<PlayerId>()) else {
+        ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+            .ok();
+        return;
+    };
+
+    match app.lobby.request_undo(&x.0, &p) {
+        UndoResult::Requested(votes) => {
+            info!("undo requested. game ID: {:?}, player ID: {:?}", x, p);
+            schedule_undo_timeout(io, app.lobby.clone(), x.0.clone());
+            ack.send(&Response::ok(&votes)).ok();
+            s.to(x.0.clone())
+                .emit("undo requested", &app.lobby.sequenced(&x.0, (&p,)))
+                .ok();
+        }
+        UndoResult::Waiting(votes) => {
+            ack.send(&Response::ok(&votes)).ok();
+            s.to(x.0.clone())
+                .emit("undo requested", &app.lobby.sequenced(&x.0, (&p,)))
+                .ok();
+        }
+        UndoResult::Applied(game) => {
+            info!("undo applied. game ID: {:?}", x);
+            ack.send(&Response::done()).ok();
+            let turn_secs = app.lobby.turn_secs(&x.0, app.config.turn_secs);
+            let deadline = start_turn_timer(&io, &app.lobby, &x.0, turn_secs);
+            let game = GameState { deadline, ..*game };
+            emit_timer_event(
+                &io,
+                None,
+                &app.lobby,
+                &x.0,
+                "undo applied",
+                (&game,),
+                (game.without_timer(),),
+            );
+            emit_pool_info(&io, &app.lobby, &app.anime_info.jikan, &x.0);
+            emit_turn_start(&io, &app.lobby, &x.0);
+        }
+        UndoResult::NothingToUndo => {
+            ack.send(&Response::error(
+                ErrorCode::NothingToUndo,
+                "no move that can be undone",
+            ))
+            .ok();
+        }
+        UndoResult::NoGame => {
+            ack.send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+                .ok();
+        }
+    }
+}
+
+fn on_decline_undo(s: SocketRef, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "decline_undo").entered();
+    let Some(x) = s.extensions.get::<GameId>() else {
+        ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+            .ok();
+        return;
+    };
+
+    if !app.lobby.reject_undo(&x.0, false) {
+        ack.send(&Response::error(
+            ErrorCode::NothingToUndo,
+            "no undo pending",
+        ))
+        .ok();
+        return;
+    }
+
+    info!("undo declined. game ID: {:?}", x);
+    ack.send(&Response::done()).ok();
+    s.within(x.0.clone())
+        .emit("undo rejected", &app.lobby.sequenced(&x.0, ()))
+        .ok();
+}
+
+/// Tells the submitter their move couldn't be judged at all, so they can
+/// retry it. Nothing about the game changed and it is still their turn.
+fn move_failed(s: &SocketRef, ack: AckSender, mal_id: u32, e: &JikanError) {
+    ack.send(&Response::error(e.code(), e.to_string())).ok();
+    s.emit(
+        "move failed",
+        &MoveFailed {
+            mal_id,
+            reason: e.reason(),
+            message: e.to_string(),
+        },
+    )
+    .ok();
+}
+
+async fn on_send_anime(
+    s: SocketRef,
+    Data(mal_id): Data<u32>,
+    io: SocketIo,
+    app: State<Arc<AppState>>,
+    ack: AckSender,
+) {
+    let span = event_span(&s, "send anime");
+    async move {
+        if is_spectator(&s) {
+            ack.send(&Response::error(
+                ErrorCode::SpectatorsCannotPlay,
+                "spectators cannot play",
+            ))
+            .ok();
+            return;
+        }
+
+        if is_rate_limited(&s, "send anime", app.config.send_anime_rate) {
+            ack.send(&Response::error(ErrorCode::RateLimited, "rate limited"))
+                .ok();
+            return;
+        }
+
+        let Some(x) = s.extensions.get::<GameId>() else {
+            return;
+        };
+
+        let Some(p) = s.extensions.get::<PlayerId>() else {
+            return;
+        };
+
+        let Some(game) = app.lobby.game_state(&x.0) else {
+            return;
+        };
+
+        if game.phase == Phase::Finished {
+            ack.send(&Response::error(ErrorCode::GameOver, "game already over"))
+                .ok();
+            return;
+        }
+
+        if game.current_turn.as_ref() != Some(&p) {
+            info!("rejecting anime {}; not {:?}'s turn", mal_id, p);
+            s.emit("not your turn", &mal_id).ok();
+            ack.send(&Response::error(ErrorCode::NotYourTurn, "not your turn"))
+                .ok();
+            return;
+        }
+
+        let Some(&prev) = game.chain.last() else {
+            info!("no previous anime for game ID: {:?}", x);
+            return;
+        };
+
+        if game.has_played(mal_id) {
+            info!("rejecting anime {}; already in the chain", mal_id);
+            s.emit("duplicate anime", &mal_id).ok();
+            ack.send(&Response::error(
+                ErrorCode::DuplicateAnime,
+                "anime already in the chain",
+            ))
+            .ok();
+            return;
+        }
+
+        if app
+            .lobby
+            .config(&x.0)
+            .is_some_and(|c| app.config.is_blocked(&c, mal_id))
+        {
+            info!("rejecting anime {}; blocked", mal_id);
+            s.emit("blocked anime", &mal_id).ok();
+            ack.send(&Response::error(
+                ErrorCode::BlockedAnime,
+                "anime is blocked",
+            ))
+            .ok();
+            return;
+        }
+
+        let info = match app.anime_info.get(mal_id).await {
+            Ok(info) => info,
+            Err(e) if e.is_not_found() => {
+                info!("rejecting anime {}; no such anime", mal_id);
+                s.emit("unknown anime", &mal_id).ok();
+                ack.send(&Response::error(ErrorCode::UnknownAnime, "no such anime"))
+                    .ok();
+                return;
+            }
+            Err(e) => {
+                info!("failed to fetch anime {}: {}", mal_id, e);
+                move_failed(&s, ack, mal_id, &e);
+                return;
+            }
+        };
+
+        if app
+            .lobby
+            .config(&x.0)
+            .is_some_and(|c| !c.allows_type(info.anime_type.as_deref()))
+        {
+            info!(
+                "rejecting anime {}; type {:?} not allowed",
+                mal_id, info.anime_type
+            );
+            s.emit("disallowed type", &(mal_id, &info.anime_type)).ok();
+            ack.send(&Response::error(
+                ErrorCode::DisallowedType,
+                "this game doesn't allow that type of anime",
+            ))
+            .ok();
+            return;
+        }
+
+        let connections = match app.anime_info.jikan.shared_voice_actors(prev, mal_id).await {
+            Ok(connections) => connections,
+            Err(e) => {
+                info!(
+                    "failed to fetch voice actors for {} or {}: {}",
+                    prev, mal_id, e
+                );
+                move_failed(&s, ack, mal_id, &e);
+                return;
+            }
+        };
+
+        if connections.is_empty() {
+            info!(
+                "rejecting anime {}; no voice actor shared with {}",
+                mal_id, prev
+            );
+            s.emit("reject anime", &mal_id).ok();
+            ack.send(&Response::error(
+                ErrorCode::NoSharedVoiceActor,
+                "no voice actor shared with the previous anime",
+            ))
+            .ok();
+            return;
+        }
+
+        let no_reused_actors = app.lobby.config(&x.0).is_some_and(|c| c.no_reused_actors);
+        let (connections, reused): (Vec<_>, Vec<_>) = connections
+            .into_iter()
+            .partition(|c| !no_reused_actors || !game.used_actors.contains(&c.mal_id));
+
+        if connections.is_empty() {
+            info!(
+                "rejecting anime {}; every voice actor shared with {} was already used",
+                mal_id, prev
+            );
+            s.emit("connection reused", &(mal_id, &reused)).ok();
+            ack.send(&Response::error(
+                ErrorCode::ConnectionReused,
+                "every shared voice actor was already used",
+            ))
+            .ok();
+            return;
+        }
+
+        let points = connection_points(&app.anime_info.jikan, &connections).await;
+        let actors: Vec<u32> = connections.iter().map(|c| c.mal_id).collect();
+
+        let game = match app.lobby.push_anime(&x.0, &p, mal_id, points, &actors) {
+            MoveResult::Accepted(game) => game,
+            MoveResult::NotYourTurn => {
+                info!("rejecting anime {}; not {:?}'s turn", mal_id, p);
+                s.emit("not your turn", &mal_id).ok();
+                ack.send(&Response::error(ErrorCode::NotYourTurn, "not your turn"))
+                    .ok();
+                return;
+            }
+            MoveResult::Duplicate => {
+                info!("rejecting anime {}; already in the chain", mal_id);
+                s.emit("duplicate anime", &mal_id).ok();
+                ack.send(&Response::error(
+                    ErrorCode::DuplicateAnime,
+                    "anime already in the chain",
+                ))
+                .ok();
+                return;
+            }
+            MoveResult::GameOver => {
+                ack.send(&Response::error(ErrorCode::GameOver, "game already over"))
+                    .ok();
+                return;
+            }
+            MoveResult::NoGame => return,
+        };
+
+        let deadline = if game.phase == Phase::Finished {
+            None
+        } else if game.overtime.is_some() {
+            start_turn_timer(&io, &app.lobby, &x.0, SUDDEN_DEATH_SECS)
+        } else {
+            start_turn_timer(
+                &io,
+                &app.lobby,
+                &x.0,
+                app.lobby.turn_secs(&x.0, app.config.turn_secs),
+            )
+        };
+        ack.send(&Response::ok(MoveAccepted {
+            mal_id,
+            points,
+            connections: &connections,
+        }))
+        .ok();
+        let ts = timestamp();
+        emit_timer_event(
+            &io,
+            None,
+            &app.lobby,
+            &x.0,
+            "next anime",
+            (
+                &info,
+                ts,
+                &game.current_turn,
+                deadline,
+                points,
+                &connections,
+            ),
+            (
+                &info,
+                ts,
+                &game.current_turn,
+                None::<u64>,
+                points,
+                &connections,
+            ),
+        );
+        s.within(x.0.clone())
+            .emit("score", &app.lobby.sequenced(&x.0, (&game.scores,)))
+            .ok();
+        emit_team_score(&io, &app.lobby, &x.0, &game);
+        emit_pool_info(&io, &app.lobby, &app.anime_info.jikan, &x.0);
+
+        if game.phase == Phase::Finished {
+            emit_game_over(&io, &app.lobby, x.0, &game);
+        } else {
+            // any move made in sudden death wins, so this one started it
+            if game.overtime.is_some() {
+                emit_sudden_death(&io, &app.lobby, &x.0, &game, deadline);
+            }
+            emit_turn_start(&io, &app.lobby, &x.0);
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+async fn on_pass(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>, ack: AckSender) {
+    let span = event_span(&s, "pass");
+    async move {
+        if is_spectator(&s) {
+            ack.send(&Response::error(
+                ErrorCode::SpectatorsCannotPlay,
+                "spectators cannot play",
+            ))
+            .ok();
+            return;
+        }
+
+        let (Some(x), Some(p)) = (s.extensions.get::<GameId>(), s.extensions.get::<PlayerId>())
+        else {
+            return;
+        };
+
+        match app
+            .lobby
+            .pass(&x.0, &p, app.config.max_passes, app.config.pass_penalty)
+        {
+            PassResult::Passed(passes, game) => {
+                info!("player passed. game ID: {:?}, player ID: {:?}", x, p);
+                let turn_secs = if game.overtime.is_some() {
+                    SUDDEN_DEATH_SECS
+                } else {
+                    app.lobby.turn_secs(&x.0, app.config.turn_secs)
+                };
+                let deadline = start_turn_timer(&io, &app.lobby, &x.0, turn_secs);
+                ack.send(&Response::ok(PassAccepted {
+                    current_turn: game.current_turn.clone(),
+                    passes,
+                    deadline,
+                }))
+                .ok();
+                let ts = timestamp();
+                emit_timer_event(
+                    &io,
+                    Some(&s),
+                    &app.lobby,
+                    &x.0,
+                    "opponent passed",
+                    (&p, &game.current_turn, passes, deadline, ts),
+                    (&p, &game.current_turn, passes, None::<u64>, ts),
+                );
+                if app.config.pass_penalty > 0 {
+                    s.within(x.0.clone())
+                        .emit("score", &app.lobby.sequenced(&x.0, (&game.scores,)))
+                        .ok();
+                    emit_team_score(&io, &app.lobby, &x.0, &game);
+                }
+                emit_turn_start(&io, &app.lobby, &x.0);
+            }
+            PassResult::OverLimit(game) => {
+                info!("pass limit exceeded. game ID: {:?}, player ID: {:?}", x, p);
+                let passes = game.passes.get(&p).copied().unwrap_or_default();
+                ack.send(&Response::ok(PassAccepted {
+                    current_turn: None,
+                    passes,
+                    deadline: None,
+                }))
+                .ok();
+                let args = (&p, None::<PlayerId>, passes, None::<u64>, timestamp());
+                s.to(x.0.clone())
+                    .emit("opponent passed", &app.lobby.sequenced(&x.0, args))
+                    .ok();
+                emit_game_over(&io, &app.lobby, x.0, &game);
+            }
+            PassResult::NotYourTurn => {
+                ack.send(&Response::error(ErrorCode::NotYourTurn, "not your turn"))
+                    .ok();
+            }
+            PassResult::GameOver => {
+                ack.send(&Response::error(ErrorCode::GameOver, "game already over"))
+                    .ok();
+            }
+            PassResult::NoGame => {
+                ack.send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+                    .ok();
+            }
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+fn on_forfeit(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>) {
+    let _span = event_span(&s, "forfeit").entered();
+    let Some(x) = s.extensions.get::<GameId>() else {
+        return;
+    };
+
+    let Some(p) = s.extensions.get::<PlayerId>() else {
+        return;
+    };
+
+    let Some(game) = app.lobby.forfeit(&x.0, &p) else {
+        return;
+    };
+
+    info!("player forfeited. game ID: {:?}, player ID: {:?}", x, p);
+    emit_game_over(&io, &app.lobby, x.0, &game);
+}
+
+/// Strips control characters from a chat message and checks its length.
+fn sanitize_chat(text: &str) -> Result<String, &'static str> {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    let text = text.trim();
+
+    if text.is_empty() {
+        return Err("message is empty");
+    }
+    if text.chars().count() > MAX_CHAT_LEN {
+        return Err("message is too long");
+    }
+
+    Ok(text.to_string())
+}
+
+fn on_chat(s: SocketRef, Data(text): Data<String>, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "chat").entered();
+    let Some(x) = s.extensions.get::<GameId>() else {
+        ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+            .ok();
+        return;
+    };
+
+    if is_spectator(&s) && !app.config.spectator_chat {
+        ack.send(&Response::error(
+            ErrorCode::SpectatorsCannotPlay,
+            "spectators cannot chat",
+        ))
+        .ok();
+        return;
+    }
+
+    let text = match sanitize_chat(&text) {
+        Ok(text) => text,
+        Err(e) => {
+            ack.send(&Response::error(ErrorCode::InvalidMessage, e))
+                .ok();
+            return;
+        }
+    };
+
+    // spectators have no player id or name and are sent as `null`
+    let sender = s.extensions.get::<PlayerId>();
+    let name = sender
+        .as_ref()
+        .and_then(|p| app.lobby.display_name(&x.0, p));
+    ack.send(&Response::done()).ok();
+    s.within(x.0.clone())
+        .emit(
+            "chat message",
+            &app.lobby.sequenced(&x.0, (sender, text, timestamp(), name)),
+        )
+        .ok();
+}
+
+/// An anime that could be played next, listed by `get_valid_moves`.
+#[derive(Serialize, Debug)]
+struct ValidMove {
+    mal_id: u32,
+    title: String,
+    /// The voice actor it would link through.
+    via: String,
+}
+
+/// Acks a sample of anime that would be accepted after the current one, for
+/// games with hints turned on. Only a handful of the current anime's voice
+/// actors are looked at, so the sample is far from exhaustive.
+async fn on_get_valid_moves(s: SocketRef, app: State<Arc<AppState>>, ack: AckSender) {
+    let span = event_span(&s, "get_valid_moves");
+    async move {
+        if is_spectator(&s) {
+            ack.send(&Response::error(
+                ErrorCode::SpectatorsCannotPlay,
+                "spectators cannot play",
+            ))
+            .ok();
+            return;
+        }
+
+        let Some(g) = s.extensions.get::<GameId>() else {
+            ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+                .ok();
+            return;
+        };
+
+        let (Some(game), Some(config)) = (app.lobby.game_state(&g.0), app.lobby.config(&g.0))
+        else {
+            ack.send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+                .ok();
+            return;
+        };
+
+        if !config.hints {
+            ack.send(&Response::error(
+                ErrorCode::HintsDisabled,
+                "hints are turned off for this game",
+            ))
+            .ok();
+            return;
+        }
+
+        if is_rate_limited(&s, "get_valid_moves", VALID_MOVES_RATE) {
+            ack.send(&Response::error(ErrorCode::RateLimited, "rate limited"))
+                .ok();
+            return;
+        }
+
+        let Some(&current) = game.chain.last().filter(|_| game.phase != Phase::Finished) else {
+            ack.send(&Response::error(
+                ErrorCode::GameNotStarted,
+                "no game in progress",
+            ))
+            .ok();
+            return;
+        };
+
+        let jikan = &app.anime_info.jikan;
+        let cast = match jikan.japanese_voice_actors(current).await {
+            Ok(cast) => cast,
+            Err(e) => {
+                info!("failed to fetch the cast of {}: {}", current, e);
+                ack.send(&Response::error(e.code(), e.to_string())).ok();
+                return;
+            }
+        };
+
+        let mut actors: Vec<(u32, Credit)> = cast
+            .into_iter()
+            .filter(|(id, _)| !config.no_reused_actors || !game.used_actors.contains(id))
+            .collect();
+        actors.shuffle(&mut rand::thread_rng());
+
+        let mut moves: Vec<ValidMove> = Vec::new();
+        for (person_id, credit) in actors.into_iter().take(VALID_MOVES_ACTORS) {
+            // a partial sample beats none when one lookup fails
+            let Ok(roles) = jikan.voice_roles(person_id).await else {
+                continue;
+            };
+            for anime in roles {
+                let playable = !game.has_played(anime.mal_id)
+                    && !app.config.is_blocked(&config, anime.mal_id)
+                    && !moves.iter().any(|m| m.mal_id == anime.mal_id);
+                if playable {
+                    moves.push(ValidMove {
+                        mal_id: anime.mal_id,
+                        title: anime.title,
+                        via: credit.name.clone(),
+                    });
+                }
+            }
+        }
+        moves.shuffle(&mut rand::thread_rng());
+        moves.truncate(MAX_VALID_MOVES);
+
+        ack.send(&Response::ok(moves)).ok();
+    }
+    .instrument(span)
+    .await
+}
+
+/// Relays that the player whose turn it is has started typing a guess. The
+/// indicator isn't cleared by a separate event: clients drop it on the next
+/// `turn start`, `turn timeout` or `game over`, so a move, pass or timeout
+/// clears it.
+fn on_thinking(s: SocketRef, app: State<Arc<AppState>>) {
+    let _span = event_span(&s, "thinking").entered();
+    let (Some(x), Some(p)) = (s.extensions.get::<GameId>(), s.extensions.get::<PlayerId>()) else {
+        return;
+    };
+
+    let on_turn = app.lobby.game_state(&x.0).is_some_and(|game| {
+        game.phase != Phase::Finished && game.current_turn.as_ref() == Some(&p)
+    });
+    if !on_turn || is_rate_limited(&s, "thinking", THINKING_RATE) {
+        return;
+    }
+
+    s.to(x.0.clone())
+        .emit("opponent thinking", &app.lobby.sequenced(&x.0, (&p,)))
+        .ok();
+}
+
+fn on_extend(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "extend").entered();
+    if is_spectator(&s) {
+        ack.send(&Response::error(
+            ErrorCode::SpectatorsCannotPlay,
+            "spectators cannot play",
+        ))
+        .ok();
+        return;
+    }
+
+    let Some(x) = s.extensions.get::<GameId>() else {
+        return;
+    };
+
+    let Some(p) = s.extensions.get::<PlayerId>() else {
+        return;
+    };
+
+    let (deadline, remaining) = match app.lobby.take_extend(&x.0, &p, app.config.max_extends) {
+        ExtendResult::Extended(deadline, remaining) => (deadline, remaining),
+        ExtendResult::Exhausted => {
+            ack.send(&Response::error(
+                ErrorCode::ExtendUnavailable,
+                "no extensions left this turn",
+            ))
+            .ok();
+            return;
+        }
+        ExtendResult::Untimed => {
+            ack.send(&Response::error(
+                ErrorCode::ExtendUnavailable,
+                "this game has no turn timer",
+            ))
+            .ok();
+            return;
+        }
+        ExtendResult::NoTurn => {
+            ack.send(&Response::error(
+                ErrorCode::ExtendUnavailable,
+                "no turn is running",
+            ))
+            .ok();
+            return;
+        }
+        ExtendResult::NotYourTurn => {
+            ack.send(&Response::error(ErrorCode::NotYourTurn, "not your turn"))
+                .ok();
+            return;
+        }
+    };
+
+    let deadline = schedule_turn_timeout(
+        io.clone(),
+        app.lobby.clone(),
+        x.0.clone(),
+        deadline + app.config.extend_secs,
+    );
+
+    ack.send(&Response::ok(remaining)).ok();
+    emit_timer_event(
+        &io,
+        None,
+        &app.lobby,
+        &x.0,
+        "extend",
+        (deadline, remaining),
+        (None::<u64>, remaining),
+    );
+    emit_turn_start(&io, &app.lobby, &x.0);
+}
+
+/// Ties `s` to the seat the lobby just gave `player_id` in `game_id`, and
+/// puts it in the game's room.
+fn seat_socket(s: &SocketRef, app: &AppState, game_id: &str, player_id: &str) {
+    app.queue.remove(s.id);
+    s.extensions.insert(PlayerId(player_id.to_string()));
+    s.extensions.insert(GameId(game_id.to_string()));
+    s.extensions.insert(Role::Player);
+    counter!("players_joined_total").increment(1);
+    let _ = s.join(game_id.to_string());
+}
+
+fn on_find_match(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "find_match").entered();
+    if s.extensions.get::<GameId>().is_some() {
+        ack.send(&Response::error(
+            ErrorCode::AlreadyInGame,
+            "leave your current game first",
+        ))
+        .ok();
+        return;
+    }
+
+    let (a, b) = match app.queue.enqueue(s.id) {
+        Queued::Duplicate => {
+            ack.send(&Response::error(
+                ErrorCode::AlreadyQueued,
+                "already waiting for a match",
+            ))
+            .ok();
+            return;
+        }
+        Queued::Waiting => {
+            info!("socket {:?} queued for a match", s.id);
+            ack.send(&Response::done()).ok();
+            return;
+        }
+        Queued::Matched(a, b) => {
+            ack.send(&Response::done()).ok();
+            (a, b)
+        }
+    };
+
+    // one side may have gone in the meantime; the other waits for someone new
+    let (Some(a), Some(b)) = (io.get_socket(a), io.get_socket(b)) else {
+        for sid in [a, b] {
+            if io.get_socket(sid).is_some() {
+                app.queue.requeue(sid);
+            }
+        }
+        return;
+    };
+
+    let game_config = GameConfig {
+        max_players: Some(2),
+        ..Default::default()
+    }
+    .normalized(&app.config);
+    let game_id = app.lobby.reserve(&app.config, game_config);
+    counter!("games_created_total").increment(1);
+
+    info!("matched {:?} and {:?} into game {:?}", a.id, b.id, game_id);
+    // seated here and now so nobody else can take the code's seats
+    for socket in [a, b] {
+        let player_id = nanoid!();
+        let LobbyResult::Joined(reply) =
+            app.lobby
+                .insert(game_id.clone(), player_id.clone(), None, None)
+        else {
+            warn!(
+                "no seat for matched socket {:?} in {:?}",
+                socket.id, game_id
+            );
+            continue;
+        };
+        seat_socket(&socket, &app, &game_id, &player_id);
+        socket.emit("match found", &(&game_id, &reply)).ok();
+    }
+    emit_room_info(&io, &app.lobby, &game_id);
+}
+
+fn on_get_time(s: SocketRef, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "get_time").entered();
+    let Some(g) = s.extensions.get::<GameId>() else {
+        ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+            .ok();
+        return;
+    };
+
+    match app.lobby.game_state(&g.0) {
+        Some(game) if is_spectator(&s) && app.lobby.hides_timer(&g.0) => ack
+            .send(&Response::ok(TurnTime::from(&game.without_timer())))
+            .ok(),
+        Some(game) => ack.send(&Response::ok(TurnTime::from(&game))).ok(),
+        None => ack
+            .send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+            .ok(),
+    };
+}
+
+/// Reply to `time_sync`.
+#[derive(Serialize, Debug)]
+struct TimeSync {
+    /// Whatever unix millis the client sent, echoed back.
+    client_time: Option<u64>,
+    server_time: u64,
+}
+
+/// Echoes the server clock so a client can work out its offset from it:
+/// with `t0` sent and `t1` the ack's arrival, the round trip is `t1 - t0`
+/// and the offset about `server_time - (t0 + t1) / 2`.
+fn on_time_sync(s: SocketRef, Data(data): Data<Value>, ack: AckSender) {
+    let _span = event_span(&s, "time_sync").entered();
+    ack.send(&Response::ok(TimeSync {
+        client_time: data.as_u64(),
+        server_time: timestamp_millis(),
+    }))
+    .ok();
+}
+
+fn on_whoami(s: SocketRef, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "whoami").entered();
+    let Some(g) = s.extensions.get::<GameId>() else {
+        ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+            .ok();
+        return;
+    };
+
+    let in_progress = app
+        .lobby
+        .game_state(&g.0)
+        .is_some_and(|game| game.status() == "in_progress");
+    ack.send(&Response::ok(WhoAmI {
+        game_id: g.0,
+        player_id: s.extensions.get::<PlayerId>(),
+        role: s.extensions.get::<Role>().unwrap_or(Role::Player),
+        in_progress,
+    }))
+    .ok();
+}
+
+/// Replies with the whole game, `seq` included, for a client that noticed a
+/// gap in the event sequence numbers.
+fn on_resync(s: SocketRef, app: State<Arc<AppState>>, ack: AckSender) {
+    let _span = event_span(&s, "resync").entered();
+    let Some(g) = s.extensions.get::<GameId>() else {
+        ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+            .ok();
+        return;
+    };
+
+    match app.lobby.game_state(&g.0) {
+        Some(game) if is_spectator(&s) && app.lobby.hides_timer(&g.0) => {
+            ack.send(&Response::ok(game.without_timer())).ok()
+        }
+        Some(game) => ack.send(&Response::ok(&game)).ok(),
+        None => ack
+            .send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+            .ok(),
+    };
+}
+
+fn on_set_ready(s: SocketRef, Data(ready): Data<bool>, app: State<Arc<AppState>>) {
+    let _span = event_span(&s, "set_ready").entered();
+    let (Some(g), Some(p)) = (s.extensions.get::<GameId>(), s.extensions.get::<PlayerId>()) else {
+        return;
+    };
+
+    if let Some(ready) = app.lobby.set_ready(&g.0, &p, ready) {
+        s.within(g.0.clone())
+            .emit("ready state", &app.lobby.sequenced(&g.0, (&ready,)))
+            .ok();
+    }
+}
+
+fn on_kick_player(
+    s: SocketRef,
+    Data(player_id): Data<String>,
+    io: SocketIo,
+    app: State<Arc<AppState>>,
+    ack: AckSender,
+) {
+    let _span = event_span(&s, "kick_player").entered();
+    let (Some(g), Some(p)) = (s.extensions.get::<GameId>(), s.extensions.get::<PlayerId>()) else {
+        ack.send(&Response::error(ErrorCode::NotInGame, "not in a game"))
+            .ok();
+        return;
+    };
+
+    let Some(lobby) = app.lobby.lobby_state(&g.0) else {
+        ack.send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+            .ok();
+        return;
+    };
+
+    if lobby.host != p {
+        info!("non-host {:?} tried to kick {:?}", p, player_id);
+        ack.send(&Response::error(
+            ErrorCode::NotHost,
+            "only the host can kick players",
+        ))
+        .ok();
+        return;
+    }
+
+    if player_id == p.0 || !lobby.players.iter().any(|q| q.0 == player_id) {
+        ack.send(&Response::error(
+            ErrorCode::UnknownPlayer,
+            "no such guest in this lobby",
+        ))
+        .ok();
+        return;
+    }
+
+    info!(
+        "kicking player. game ID: {:?}, player ID: {:?}",
+        g, player_id
+    );
+    for target in io.within(g.0.clone()).sockets().unwrap_or_default() {
+        if target
+            .extensions
+            .get::<PlayerId>()
+            .is_some_and(|q| q.0 == player_id)
+        {
+            target.extensions.remove::<GameId>();
+            target.extensions.remove::<PlayerId>();
+            target.extensions.remove::<Role>();
+            let _ = target.leave(g.0.clone());
+            target.emit("kicked", &g.0).ok();
+        }
+    }
+
+    ack.send(&Response::done()).ok();
+    remove_player(&io, &app.lobby, g.0, PlayerId(player_id));
+}
+
+fn on_leave_game(s: SocketRef, io: SocketIo, app: State<Arc<AppState>>) {
+    let _span = event_span(&s, "leave_game").entered();
+    let Some(g) = s.extensions.remove::<GameId>() else {
+        return;
+    };
+
+    let _ = s.leave([g.0.clone(), spectator_room(&g.0)]);
+
+    if s.extensions.remove::<Role>() == Some(Role::Spectator) {
+        info!("spectator left. game ID: {:?}", g);
+        let token = s.extensions.remove::<SpectatorToken>();
+        if let Some(spectators) = token.and_then(|t| app.lobby.remove_spectator(&g.0, &t.0, s.id)) {
+            s.within(g.0.clone())
+                .emit("spectators", &app.lobby.sequenced(&g.0, (spectators,)))
+                .ok();
+            emit_room_info(&io, &app.lobby, &g.0);
+        }
+        return;
+    }
+
+    let Some(p) = s.extensions.remove::<PlayerId>() else {
+        return;
+    };
+
+    info!("leaving game. game ID: {:?}, player ID: {:?}", g, p);
+    remove_player(&io, &app.lobby, g.0, p);
+}
+
+/// Pings the socket every `interval`, disconnecting it after `misses`
+/// consecutive pings go unanswered so a half-open connection goes through the
+/// usual disconnect and eviction path instead of holding its slot.
+async fn heartbeat(s: SocketRef, interval: Duration, misses: u32) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    let mut missed = 0;
+
+    while s.connected() {
+        ticker.tick().await;
+
+        let answered = match s.emit_with_ack::<_, serde::de::IgnoredAny>("ping", &timestamp()) {
+            Ok(ack) => matches!(tokio::time::timeout(interval, ack).await, Ok(Ok(_))),
+            Err(_) => return,
+        };
+
+        missed = if answered { 0 } else { missed + 1 };
+        if missed >= misses {
+            info!("socket {:?} missed {} pings; disconnecting", s.id, missed);
+            s.disconnect().ok();
+            return;
+        }
+    }
+}
+
+fn on_connect(socket: SocketRef, Data(data): Data<Value>, app: State<Arc<AppState>>) {
+    let span = info_span!("socket", sid = %socket.id);
+    let _span = span.enter();
+    socket.extensions.insert(SocketSpan(span.clone()));
+    info!(ns = socket.ns(), "Socket.IO connected");
+    gauge!("connected_sockets").increment(1);
+    app.sockets.increment();
+    socket.extensions.insert(RateLimiter::default());
+    socket.emit("auth", &data).ok();
+
+    if !app.config.heartbeat_interval.is_zero() {
+        tokio::spawn(heartbeat(
+            socket.clone(),
+            app.config.heartbeat_interval,
+            app.config.heartbeat_misses,
+        ));
+    }
+
+    socket.on(
+        "join_game",
+        |s: SocketRef,
+         Data::<EventData>(mut data),
+         io: SocketIo,
+         app: State<Arc<AppState>>,
+         ack: AckSender| {
+            let span = event_span(&s, "join_game");
+            let _span = span.enter();
+            // one game per socket; `leave_game` first to switch
+            if s.extensions.get::<GameId>().is_some() {
+                ack.send(&Response::error(
+                    ErrorCode::AlreadyInGame,
+                    "leave your current game first",
+                ))
+                .ok();
+                return;
+            }
+
+            if data.player_id.is_empty() {
+                data.player_id = nanoid!();
+            }
+
+            if let Err(e) = data.validate() {
+                info!("invalid join payload: {}", e);
+                ack.send(&Response::error(ErrorCode::InvalidPayload, e))
+                    .ok();
+                return;
+            }
+            span.record("game_id", data.game_id.as_str());
+            span.record("player_id", data.player_id.as_str());
+
+            if let Some(code) = &data.challenge {
+                match GameConfig::from_challenge_code(code) {
+                    Ok(config) => data.config = Some(config),
+                    Err(e) => {
+                        info!("invalid challenge code {:?}: {}", code, e);
+                        ack.send(&Response::error(ErrorCode::InvalidChallenge, e))
+                            .ok();
+                        return;
+                    }
+                }
+            }
+
+            if let Some(Err(e)) = data.config.as_ref().map(GameConfig::validate) {
+                info!("invalid game config {:?}: {}", data.config, e);
+                ack.send(&Response::error(ErrorCode::InvalidConfig, e)).ok();
+                return;
+            }
+
+            let display_name = data.display_name.as_deref().and_then(sanitize_display_name);
+            let res = app.lobby.insert(
+                data.game_id.clone(),
+                data.player_id.clone(),
+                display_name,
+                data.team,
+            );
+            info!("lobby {:?}", app.lobby);
+
+            if matches!(res, LobbyResult::Joined(_)) {
+                seat_socket(&s, &app, &data.game_id, &data.player_id);
+            }
+
+            let lobby = match res {
+                LobbyResult::Joined(mut reply) => {
+                    // held to the same limits and defaults as `POST /game`
+                    if reply.role == PlayerRole::Host {
+                        if let Some(config) = data.config.clone() {
+                            reply.config = config.normalized(&app.config);
+                            app.lobby.set_config(&data.game_id, reply.config.clone());
+                        }
+                    }
+                    ack.send(&Response::ok(&reply)).ok();
+                    reply.lobby
+                }
+                LobbyResult::Full(room) => {
+                    info!("lobby is full");
+                    ack.send(&Response::error_with(
+                        ErrorCode::RoomFull,
+                        "room is full",
+                        room,
+                    ))
+                    .ok();
+                    return;
+                }
+                LobbyResult::DuplicateId => {
+                    info!("duplicate player ID: {:?}", data.player_id);
+                    ack.send(&Response::error(
+                        ErrorCode::DuplicateId,
+                        "duplicate player id",
+                    ))
+                    .ok();
+                    return;
+                }
+                LobbyResult::UnknownGame => {
+                    info!("unknown game ID: {:?}", data.game_id);
+                    ack.send(&Response::error(ErrorCode::UnknownGame, "unknown game"))
+                        .ok();
+                    return;
+                }
+            };
+
+            s.to(data.game_id.clone())
+                .emit(
+                    "player joined",
+                    &app.lobby
+                        .sequenced(&data.game_id, (&data.player_id, &lobby)),
+                )
+                .ok();
+            emit_room_info(&io, &app.lobby, &data.game_id);
+
+            if let Some(game) = app.lobby.game_state(&data.game_id) {
+                if !game.chain.is_empty() {
+                    s.emit("game state", &game).ok();
+                    s.emit("score", &game.scores).ok();
+                }
+            }
+        },
+    );
+
+    socket.on(
+        "rejoin_game",
+        |s: SocketRef,
+         Data::<RejoinData>(data),
+         io: SocketIo,
+         app: State<Arc<AppState>>,
+         ack: AckSender| {
+            let span = event_span(&s, "rejoin_game");
+            let _span = span.enter();
+            span.record("game_id", data.game_id.as_str());
+            if s.extensions.get::<GameId>().is_some() {
+                ack.send(&Response::error(
+                    ErrorCode::AlreadyInGame,
+                    "leave your current game first",
+                ))
+                .ok();
+                return;
+            }
+
+            // a spectator's token only ever gets them their seat back
+            if app.lobby.is_spectator_token(&data.game_id, &data.token) {
+                spectate(&s, &io, &app, data.game_id, Some(data.token), ack);
+                return;
+            }
+
+            let Some(player_id) = app.lobby.rejoin(&data.game_id, &data.token) else {
+                info!("invalid rejoin. game ID: {:?}", data.game_id);
+                ack.send(&Response::error(ErrorCode::InvalidToken, "invalid token"))
+                    .ok();
+                return;
+            };
+            span.record("player_id", player_id.0.as_str());
+
+            info!(
+                "player rejoined. game ID: {:?}, player ID: {:?}",
+                data.game_id, player_id
+            );
+            s.extensions.insert(player_id.clone());
+            s.extensions.insert(GameId(data.game_id.clone()));
+            s.extensions.insert(Role::Player);
+            ack.send(&Response::done()).ok();
+
+            let _ = s.join(data.game_id.clone());
+            let deadline = resume_turn(&io, &app.lobby, data.game_id.clone());
+            emit_timer_event(
+                &io,
+                Some(&s),
+                &app.lobby,
+                &data.game_id,
+                "opponent reconnected",
+                (&player_id, deadline),
+                (&player_id, None::<u64>),
+            );
+
+            if let Some(game) = app.lobby.game_state(&data.game_id) {
+                s.emit("game state", &game).ok();
+                s.emit("score", &game.scores).ok();
+                // a resumed timer already announced the turn to the room
+                if let Some(turn) = TurnStart::of(&game).filter(|_| deadline.is_none()) {
+                    s.emit("turn start", &turn).ok();
+                }
+                emit_chain_sync(&s, &app.anime_info, game);
+            }
+        },
+    );
+
+    socket.on(
+        "spectate_game",
+        |s: SocketRef,
+         Data::<SpectateData>(data),
+         io: SocketIo,
+         app: State<Arc<AppState>>,
+         ack: AckSender| {
+            let (game_id, token) = data.into_parts();
+            let span = event_span(&s, "spectate_game");
+            let _span = span.enter();
+            span.record("game_id", game_id.as_str());
+            if s.extensions.get::<GameId>().is_some() {
+                ack.send(&Response::error(
+                    ErrorCode::AlreadyInGame,
+                    "leave your current game first",
+                ))
+                .ok();
+                return;
+            }
+
+            spectate(&s, &io, &app, game_id, token, ack);
+        },
+    );
+
+    socket.on("leave_game", on_leave_game);
+    socket.on("kick_player", on_kick_player);
+    socket.on("set_ready", on_set_ready);
+    socket.on("get_time", on_get_time);
+    socket.on("resync", on_resync);
+    socket.on("whoami", on_whoami);
+    socket.on("time_sync", on_time_sync);
+    socket.on("find_match", on_find_match);
+    socket.on("start game", start_game);
+    socket.on("reroll_seed", on_reroll_seed);
+    socket.on("pass", on_pass);
+    socket.on("extend", on_extend);
+    socket.on("thinking", on_thinking);
+    socket.on("get_valid_moves", on_get_valid_moves);
+    socket.on("forfeit", on_forfeit);
+    socket.on("chat", on_chat);
+    socket.on("request_rematch", on_request_rematch);
+    socket.on("request_undo", on_request_undo);
+    socket.on("decline_undo", on_decline_undo);
+
+    socket.on("send anime", on_send_anime);
+
+    socket.on("message-with-ack", |Data::<Value>(data), ack: AckSender| {
+        info!(?data, "Received event");
+        ack.send(&("replied: ".to_owned() + data.as_str().unwrap()))
+            .ok();
+    });
+
+    socket.on_disconnect(|s: SocketRef, io: SocketIo, app: State<Arc<AppState>>| {
+        let _span = event_span(&s, "disconnect").entered();
+        gauge!("connected_sockets").decrement(1);
+        app.sockets.decrement();
+        app.queue.remove(s.id);
+
+        let Some(g) = s.extensions.get::<GameId>() else {
+            info!("Disconnected with no game ID");
+            return;
+        };
+
+        if is_spectator(&s) {
+            info!("Spectator disconnected with game ID: {:?}", g);
+            let token = s.extensions.get::<SpectatorToken>();
+            if let Some(spectators) =
+                token.and_then(|t| app.lobby.detach_spectator(&g.0, &t.0, s.id))
+            {
+                s.to(g.0.clone())
+                    .emit("spectators", &app.lobby.sequenced(&g.0, (spectators,)))
+                    .ok();
+                emit_room_info(&io, &app.lobby, &g.0);
+            }
+            return;
+        }
+
+        let Some(p) = s.extensions.get::<PlayerId>() else {
+            info!("Disconnected with no player ID");
+            return;
+        };
+
+        info!("Disconnected with game ID: {:?}, player ID: {:?}", g, p);
+        app.lobby.pause_timer(&g.0);
+        app.lobby.withdraw_rematch(&g.0, &p);
+        s.to(g.0.clone())
+            .emit("opponent disconnected", &app.lobby.sequenced(&g.0, (&p,)))
+            .ok();
+        app.lobby
+            .schedule_eviction(io, g.0, p, app.config.reconnect_grace);
+    });
+}
+
+/// Periodically reaps lobbies that were abandoned before or during a game.
+async fn sweep_lobbies(lobby: Lobby, ttl: Duration) {
+    let mut interval = tokio::time::interval(LOBBY_SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let reaped = lobby.sweep(ttl);
+        info!("reaped {} abandoned lobbies", reaped);
+    }
+}
+
+/// Periodically logs how often Jikan lookups were answered from the cache.
+async fn report_cache_stats(jikan: Jikan) {
+    let mut interval = tokio::time::interval(JIKAN_CACHE_REPORT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        for (endpoint, (hits, misses)) in [
+            ("characters", jikan.casts.take_stats()),
+            ("voices", jikan.roles.take_stats()),
+        ] {
+            let lookups = hits + misses;
+            if lookups > 0 {
+                info!(
+                    "jikan {} cache: {} hits, {} misses ({:.0}% hit rate)",
+                    endpoint,
+                    hits,
+                    misses,
+                    hits as f64 * 100.0 / lookups as f64
+                );
+            }
+        }
+    }
+}
+
+/// Looks up a player's record. Player ids are chosen by clients, so these
+/// numbers are only as trustworthy as the ids themselves.
+async fn get_player_stats(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(player_id): axum::extract::Path<String>,
+) -> Result<axum::Json<PlayerStats>, StatusCode> {
+    app.lobby
+        .stats
+        .get(&PlayerId(player_id))
+        .map(axum::Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// A voice actor on an anime, as listed by `GET /anime/{id}/actors`.
+#[derive(Serialize, Debug)]
+struct CastMember {
+    mal_id: u32,
+    name: String,
+    characters: Vec<Character>,
+}
+
+/// Lists the Japanese voice actors on an anime, ordered by name, so clients
+/// can offer hints without calling Jikan themselves.
+async fn get_anime_actors(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(mal_id): axum::extract::Path<u32>,
+) -> Result<axum::Json<Vec<CastMember>>, StatusCode> {
+    let cast = match app.anime_info.jikan.japanese_voice_actors(mal_id).await {
+        Ok(cast) => cast,
+        Err(e) if e.is_not_found() => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("failed to fetch the cast of {}: {}", mal_id, e);
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let mut actors: Vec<CastMember> = cast
+        .into_iter()
+        .map(|(mal_id, credit)| CastMember {
+            mal_id,
+            name: credit.name,
+            characters: credit.characters,
+        })
+        .collect();
+    actors.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(axum::Json(actors))
+}
+
+/// Reply to `POST /challenge`.
+#[derive(Serialize, Debug)]
+struct CreatedChallenge {
+    code: String,
+    config: GameConfig,
+}
+
+/// Turns a ruleset, given as the JSON body, into a challenge code that
+/// `join_game` can open a lobby from. Picks the seed anime now unless the
+/// body names one, so everyone playing the code starts from the same anime.
+async fn create_challenge(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+    body: axum::body::Bytes,
+) -> Result<axum::Json<CreatedChallenge>, (StatusCode, String)> {
+    let game_config = if body.is_empty() {
+        GameConfig::default()
+    } else {
+        serde_json::from_slice::<GameConfig>(&body)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    };
+    game_config
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let mut game_config = game_config.normalized(&app.config);
+
+    if game_config.seed_anime.is_none() {
+        let ids = app
+            .top_anime
+            .get(&game_config.seed_path())
+            .await
+            .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+        let allowed: Vec<u32> = ids
+            .iter()
+            .copied()
+            .filter(|&id| !app.config.is_blocked(&game_config, id))
+            .collect();
+        let seed = choose_anime(&allowed, &mut app.config.seed_source.rng()).ok_or((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "every anime in the pool is blocked".to_string(),
+        ))?;
+        game_config.seed_anime = Some(seed);
+    }
+
+    Ok(axum::Json(CreatedChallenge {
+        code: game_config.challenge_code(),
+        config: game_config,
+    }))
+}
+
+/// Reply to `POST /game`.
+#[derive(Serialize, Debug)]
+struct CreatedGame {
+    game_id: String,
+    config: GameConfig,
+}
+
+/// Reserves a game id, optionally with a ruleset given as the JSON body.
+async fn create_game(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+    body: axum::body::Bytes,
+) -> Result<axum::Json<CreatedGame>, (StatusCode, String)> {
+    let game_config = if body.is_empty() {
+        GameConfig::default()
+    } else {
+        serde_json::from_slice::<GameConfig>(&body)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    };
+    game_config
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let game_config = game_config.normalized(&app.config);
+
+    let game_id = app.lobby.reserve(&app.config, game_config.clone());
+    counter!("games_created_total").increment(1);
+
+    Ok(axum::Json(CreatedGame {
+        game_id,
+        config: game_config,
+    }))
+}
+
+/// Reply to `GET /version`.
+#[derive(Serialize, Debug)]
+struct VersionInfo {
+    version: &'static str,
+    commit: &'static str,
+    /// Unix seconds at which the binary was built.
+    built_at: u64,
+}
+
+/// Which build is running, for matching a deploy to its commit.
+async fn get_version() -> axum::Json<VersionInfo> {
+    axum::Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("GIT_COMMIT"),
+        built_at: env!("BUILD_TIMESTAMP").parse().unwrap_or_default(),
+    })
+}
+
+async fn get_metrics(axum::extract::State(app): axum::extract::State<Arc<AppState>>) -> String {
+    app.metrics.render()
+}
+
+/// Readiness probe: 503 once enough Jikan calls in a row have failed that
+/// games probably can't be started.
+async fn get_ready(axum::extract::State(app): axum::extract::State<Arc<AppState>>) -> StatusCode {
+    if app.anime_info.jikan.is_reachable() {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+/// Headline counts for a status page; unlike `/admin/state` this exposes
+/// nothing about individual games.
+async fn get_stats(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+) -> axum::Json<ServerStats> {
+    let (lobbies, games_in_progress) = app.lobby.counts();
+    axum::Json(ServerStats {
+        lobbies,
+        games_in_progress,
+        sockets: app.sockets.get(),
+    })
+}
+
+async fn list_lobbies(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+) -> axum::Json<Vec<LobbySummary>> {
+    axum::Json(app.lobby.joinable())
+}
+
+async fn get_chain(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(game_id): axum::extract::Path<String>,
+) -> Result<axum::Json<ChainResponse>, StatusCode> {
+    let game = app
+        .lobby
+        .game_state(&game_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let chain = futures::future::join_all(game.chain.iter().map(|&mal_id| {
+        let anime_info = app.anime_info.clone();
+        async move {
+            ChainLink {
+                mal_id,
+                title: anime_info.get(mal_id).await.ok().map(|info| info.title),
+            }
+        }
+    }))
+    .await;
+
+    Ok(axum::Json(ChainResponse {
+        chain,
+        score: game.score,
+        status: game.status(),
+    }))
+}
+
+async fn get_history(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+    axum::extract::Path(game_id): axum::extract::Path<String>,
+) -> Result<axum::Json<Vec<TurnRecord>>, StatusCode> {
+    app.lobby
+        .game_state(&game_id)
+        .map(|game| axum::Json(game.history))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Body of the errors the HTTP routes answer with themselves.
+#[derive(Serialize, Debug)]
+struct HttpError {
+    error: String,
+}
+
+async fn not_found(uri: http::Uri) -> (StatusCode, axum::Json<HttpError>) {
+    let error = format!("no route for {}", uri.path());
+    (StatusCode::NOT_FOUND, axum::Json(HttpError { error }))
+}
+
+async fn method_not_allowed(
+    method: http::Method,
+    uri: http::Uri,
+) -> (StatusCode, axum::Json<HttpError>) {
+    let error = format!("{} is not allowed on {}", method, uri.path());
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        axum::Json(HttpError { error }),
+    )
+}
+
+async fn admin_state(
+    axum::extract::State(app): axum::extract::State<Arc<AppState>>,
+    headers: http::HeaderMap,
+) -> Result<axum::Json<HashMap<String, LobbySnapshot>>, StatusCode> {
+    if !app
+        .admin_token
+        .authorizes(headers.get(http::header::AUTHORIZATION))
+    {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(axum::Json(app.lobby.snapshot()))
+}
+
+/// Resolves once SIGINT or SIGTERM is received.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Warns every connected client that the server is going away, gives
+/// in-flight Jikan requests `SHUTDOWN_DRAIN` to settle, then closes all sockets.
+async fn shutdown(io: SocketIo) {
+    shutdown_signal().await;
+
+    info!("shutting down; draining for {:?}", SHUTDOWN_DRAIN);
+    io.emit("server shutting down", &SHUTDOWN_DRAIN.as_secs())
+        .ok();
+    tokio::time::sleep(SHUTDOWN_DRAIN).await;
+    io.close().await;
+}
+
+/// Resolves the listen address from `BIND_ADDR` (default `0.0.0.0:3000`),
+/// with `PORT` overriding just the port as PaaS providers expect.
+fn bind_addr(bind: Option<String>, port: Option<String>) -> Result<SocketAddr, String> {
+    let bind = bind.unwrap_or("0.0.0.0:3000".to_string());
+    let mut addr: SocketAddr = bind
+        .parse()
+        .map_err(|e| format!("invalid BIND_ADDR {:?}: {}", bind, e))?;
+
+    if let Some(port) = port {
+        addr.set_port(
+            port.parse()
+                .map_err(|e| format!("invalid PORT {:?}: {}", port, e))?,
+        );
+    }
+
+    Ok(addr)
+}
+
+/// One entry of the CORS allowlist.
+#[derive(Debug, Clone, PartialEq)]
+enum OriginRule {
+    /// Exactly this origin, such as `https://ani2nerdle.app`.
+    Exact(String),
+    /// Any subdomain of `suffix` over `scheme`, written `*.myapp.vercel.app`
+    /// (https implied) or `http://*.localhost:3000`. The bare suffix itself
+    /// doesn't match.
+    Subdomain { scheme: String, suffix: String },
+}
+
+impl OriginRule {
+    fn parse(raw: &str) -> Result<Self, &'static str> {
+        if !raw.contains('*') {
+            raw.parse::<HeaderValue>()
+                .or(Err("not a valid header value"))?;
+            return Ok(OriginRule::Exact(raw.to_string()));
+        }
+
+        let (scheme, host) = raw.split_once("://").unwrap_or(("https", raw));
+        let suffix = host
+            .strip_prefix("*.")
+            .ok_or("a wildcard must be a leading `*.`")?;
+        let bare = !suffix.contains('.') && !suffix.contains(':');
+        if suffix.is_empty() || suffix.contains('*') || bare {
+            return Err("a wildcard needs a domain to stand under");
+        }
+
+        Ok(OriginRule::Subdomain {
+            scheme: scheme.to_ascii_lowercase(),
+            suffix: suffix.to_ascii_lowercase(),
+        })
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginRule::Exact(allowed) => allowed == origin,
+            OriginRule::Subdomain { scheme, suffix } => {
+                let origin = origin.to_ascii_lowercase();
+                let Some(host) = origin
+                    .strip_prefix(scheme.as_str())
+                    .and_then(|rest| rest.strip_prefix("://"))
+                else {
+                    return false;
+                };
+                host.strip_suffix(suffix.as_str())
+                    .and_then(|sub| sub.strip_suffix('.'))
+                    .is_some_and(|sub| {
+                        !sub.is_empty()
+                            && sub.split('.').all(|label| {
+                                !label.is_empty()
+                                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                            })
+                    })
+            }
+        }
+    }
+}
+
+/// Parses a comma-separated origin list, skipping entries that are empty or
+/// invalid instead of refusing to start.
+fn allowed_origins(raw: &str) -> Vec<OriginRule> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .filter_map(|origin| {
+            if origin == "*" {
+                warn!("ignoring wildcard CORS origin; list origins explicitly");
+                return None;
+            }
+            match OriginRule::parse(origin) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    warn!("ignoring invalid CORS origin {:?}: {}", origin, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Installs the global subscriber, filtered by `RUST_LOG` (`info` when
+/// unset) and emitting JSON lines when `LOG_FORMAT=json`.
+fn init_tracing() -> Result<(), Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing::subscriber::set_global_default(builder.json().finish())?,
+        _ => tracing::subscriber::set_global_default(builder.finish())?,
+    }
+
+    Ok(())
+}
+
+/// The HTTP routes with the Socket.IO layer on top, everything but CORS.
+fn router(app_state: Arc<AppState>) -> (axum::Router, SocketIo) {
+    let (layer, io) = SocketIo::builder()
+        .with_state(app_state.clone())
+        .build_layer();
+
+    io.ns("/", on_connect);
+
+    let app = axum::Router::new()
+        .route(
+            "/game",
+            axum::routing::post(create_game).fallback(method_not_allowed),
+        )
+        .route(
+            "/challenge",
+            axum::routing::post(create_challenge).fallback(method_not_allowed),
+        )
+        .route(
+            "/lobbies",
+            axum::routing::get(list_lobbies).fallback(method_not_allowed),
+        )
+        .route(
+            "/metrics",
+            axum::routing::get(get_metrics).fallback(method_not_allowed),
+        )
+        .route(
+            "/stats",
+            axum::routing::get(get_stats).fallback(method_not_allowed),
+        )
+        .route(
+            "/admin/state",
+            axum::routing::get(admin_state).fallback(method_not_allowed),
+        )
+        .route(
+            "/game/:id/chain",
+            axum::routing::get(get_chain).fallback(method_not_allowed),
+        )
+        .route(
+            "/game/:id/history",
+            axum::routing::get(get_history).fallback(method_not_allowed),
+        )
+        .route(
+            "/players/:id/stats",
+            axum::routing::get(get_player_stats).fallback(method_not_allowed),
+        )
+        .route(
+            "/anime/:id/actors",
+            axum::routing::get(get_anime_actors).fallback(method_not_allowed),
+        )
+        .route(
+            "/healthz",
+            axum::routing::get(|| async { StatusCode::NO_CONTENT }).fallback(method_not_allowed),
+        )
+        .route(
+            "/version",
+            axum::routing::get(get_version).fallback(method_not_allowed),
+        )
+        .route(
+            "/readyz",
+            axum::routing::get(get_ready).fallback(method_not_allowed),
+        )
+        .fallback(not_found)
+        .with_state(app_state)
+        .layer(layer);
+
+    (app, io)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_tracing()?;
+    let addr = bind_addr(env::var("BIND_ADDR").ok(), env::var("PORT").ok())?;
+    let metrics = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("jikan_request_duration_seconds".to_string()),
+            &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0],
+        )?
+        .install_recorder()?;
+
+    let store = JsonlStore::new(env::var("GAME_STORE_PATH").unwrap_or("games.jsonl".to_string()));
+    let config = Config::from_env()?;
+    info!(?config, "loaded config");
+    let lobby = Lobby::new(Arc::new(store), config.snapshot_interval);
+    let jikan = Jikan::new(
+        &env::var("JIKAN_BASE_URL").unwrap_or(JIKAN_BASE_URL.to_string()),
+        config.jikan_client()?,
+        config.jikan_cache_size,
+        config.jikan_cache_ttl,
+    );
+    tokio::spawn(report_cache_stats(jikan.clone()));
+    let anime_info = AnimeInfoCache::new(jikan);
+    let admin_token = AdminToken(env::var("ADMIN_TOKEN").ok().map(Arc::from));
+
+    tokio::spawn(sweep_lobbies(lobby.clone(), config.lobby_ttl));
+
+    let app_state = Arc::new(AppState {
+        lobby,
+        top_anime: TopAnimeCache::new(
+            config.top_anime_ttl,
+            config.seed_pool_pages,
+            anime_info.clone(),
+        ),
+        anime_info,
+        config,
+        queue: MatchQueue::default(),
+        sockets: SocketCount::default(),
+        admin_token,
+        metrics,
+    });
+    let (app, io) = router(app_state);
+
+    let origins = allowed_origins(
+        &env::var("ALLOWED_ORIGINS")
+            .or_else(|_| env::var("FRONTEND_URL"))
+            .unwrap_or("".to_string()),
+    );
+    if origins.is_empty() {
+        warn!("no CORS origins configured; cross-origin requests will be refused");
+    }
+    let credentials = matches!(
+        env::var("CORS_ALLOW_CREDENTIALS").as_deref(),
+        Ok("1") | Ok("true")
+    );
+    // the allowed origin is echoed back per request, so responses carry
+    // `Vary: Origin` (tower-http's default) to keep caches from mixing them
+    let cors = CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| origins.iter().any(|rule| rule.matches(origin)))
+        }))
+        .allow_credentials(credentials);
+
+    let app = app.layer(cors);
+
+    info!("Starting server on {}", addr);
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown(io))
+        .await?;
+
+    info!("Server stopped");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+    /// Throws finished games away instead of writing them to disk.
+    #[derive(Debug)]
+    struct NullStore;
+
+    impl GameStore for NullStore {
+        fn save(&self, _: &GameRecord) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn load(&self) -> io::Result<Vec<GameRecord>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn lobby() -> Lobby {
+        Lobby::new(Arc::new(NullStore), Duration::ZERO)
+    }
+
+    fn settings() -> Config {
+        Config::from_env().unwrap()
+    }
+
+    fn player(id: &str) -> PlayerId {
+        PlayerId(id.to_string())
+    }
+
+    /// A game between `a` and `b` seeded with anime 1, `a` to move.
+    fn started(lobby: &Lobby, a: &str, b: &str) -> String {
+        let game_id = lobby.reserve(&settings(), GameConfig::default());
+        for id in [a, b] {
+            lobby.insert(game_id.clone(), id.to_string(), None, None);
+        }
+        lobby.start(&game_id, 1, FirstTurn::Host, 0, 3);
+        game_id
+    }
+
+    /// Serves `router` on a free local port.
+    async fn spawn(router: axum::Router) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        addr
+    }
+
+    /// Serves the whole app, asking the Jikan at `jikan` about anime.
+    async fn serve(jikan: &str) -> (SocketAddr, Arc<AppState>) {
+        serve_with(settings(), jikan).await
+    }
+
+    /// Like [`serve`], under `config` instead of the default settings.
+    async fn serve_with(config: Config, jikan: &str) -> (SocketAddr, Arc<AppState>) {
+        let jikan = Jikan::new(
+            jikan,
+            config.jikan_client().unwrap(),
+            config.jikan_cache_size,
+            config.jikan_cache_ttl,
+        );
+        let anime_info = AnimeInfoCache::new(jikan);
+        let app = Arc::new(AppState {
+            lobby: lobby(),
+            top_anime: TopAnimeCache::new(
+                config.top_anime_ttl,
+                config.seed_pool_pages,
+                anime_info.clone(),
+            ),
+            anime_info,
+            config,
+            queue: MatchQueue::default(),
+            sockets: SocketCount::default(),
+            admin_token: AdminToken(None),
+            metrics: PrometheusBuilder::new().build_recorder().handle(),
+        });
+
+        (spawn(router(app.clone()).0).await, app)
+    }
+
+    /// Just enough of a Socket.IO client to drive the server over a
+    /// websocket.
+    struct Client {
+        ws: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+        /// Events that came in while waiting on something else, each as its
+        /// name followed by its arguments.
+        events: VecDeque<Vec<serde_json::Value>>,
+        acks: u32,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Self {
+            let url = format!("ws://{}/socket.io/?EIO=4&transport=websocket", addr);
+            let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+            let mut client = Client {
+                ws,
+                events: VecDeque::new(),
+                acks: 0,
+            };
+
+            while !client.recv().await.starts_with('0') {}
+            client.send("40{}".to_string()).await;
+            while !client.recv().await.starts_with("40") {}
+            client
+        }
+
+        async fn send(&mut self, packet: String) {
+            self.ws.send(Message::text(packet)).await.unwrap();
+        }
+
+        /// The next packet, answering pings on the way.
+        async fn recv(&mut self) -> String {
+            loop {
+                let next = tokio::time::timeout(Duration::from_secs(5), self.ws.next());
+                let Message::Text(packet) = next.await.expect("timed out").unwrap().unwrap() else {
+                    continue;
+                };
+                if packet == "2" {
+                    self.send("3".to_string()).await;
+                    continue;
+                }
+                return packet;
+            }
+        }
+
+        /// Keeps `packet` for [`Client::event`] if it is an event.
+        fn stash(&mut self, packet: &str) {
+            if let Some(event) = packet.strip_prefix("42") {
+                let event = event.trim_start_matches(|c: char| c.is_ascii_digit());
+                self.events.push_back(serde_json::from_str(event).unwrap());
+            }
+        }
+
+        /// Hangs up, as a client dropping off would.
+        async fn close(mut self) {
+            self.ws.close(None).await.unwrap();
+        }
+
+        /// Emits `event` without asking for an ack.
+        async fn emit(&mut self, event: &str, data: serde_json::Value) {
+            self.send(format!("42{}", json!([event, data]))).await;
+        }
+
+        /// Emits `event` and waits for the server's ack.
+        async fn call(&mut self, event: &str, data: serde_json::Value) -> serde_json::Value {
+            self.acks += 1;
+            let id = self.acks.to_string();
+            self.send(format!("42{}{}", id, json!([event, data]))).await;
+
+            loop {
+                let packet = self.recv().await;
+                let reply = packet
+                    .strip_prefix("43")
+                    .and_then(|p| p.strip_prefix(id.as_str()))
+                    .filter(|p| p.starts_with('['));
+                match reply {
+                    Some(reply) => {
+                        let mut args: Vec<serde_json::Value> = serde_json::from_str(reply).unwrap();
+                        return args.remove(0);
+                    }
+                    None => self.stash(&packet),
+                }
+            }
+        }
+
+        /// The arguments of the next `name` event, skipping any others.
+        async fn event(&mut self, name: &str) -> Vec<serde_json::Value> {
+            loop {
+                while let Some(mut event) = self.events.pop_front() {
+                    if event[0] == name {
+                        event.remove(0);
+                        return event;
+                    }
+                }
+                let packet = self.recv().await;
+                self.stash(&packet);
+            }
+        }
+    }
+
+    /// A fresh game with sockets seated as host `a` and guest `b`.
+    async fn seat_pair(addr: SocketAddr, app: &AppState) -> (String, Client, Client) {
+        let game_id = app.lobby.reserve(&app.config, GameConfig::default());
+        let mut host = Client::connect(addr).await;
+        let mut guest = Client::connect(addr).await;
+        for (client, id) in [(&mut host, "a"), (&mut guest, "b")] {
+            let reply = client
+                .call("join_game", json!({ "game_id": game_id, "player_id": id }))
+                .await;
+            assert_eq!(reply["status"], "ok");
+        }
+
+        (game_id, host, guest)
+    }
+
+    /// Seats a pair, readies them and has the host send `start game`.
+    async fn start_pair(addr: SocketAddr, app: &AppState) -> (String, Client, Client) {
+        let (game_id, mut host, guest) = seat_pair(addr, app).await;
+        for id in ["a", "b"] {
+            app.lobby.set_ready(&game_id, &player(id), true);
+        }
+        host.emit("start game", json!(null)).await;
+
+        (game_id, host, guest)
+    }
+
+    /// Waits for `done` to hold, for state a disconnect updates in the
+    /// background.
+    async fn eventually(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("condition never held");
+    }
+
+    #[test]
+    fn replayed_anime_is_a_duplicate() {
+        let lobby = lobby();
+        let game_id = started(&lobby, "a", "b");
+
+        assert!(matches!(
+            lobby.push_anime(&game_id, &player("a"), 2, 1, &[10]),
+            MoveResult::Accepted(_)
+        ));
+        assert!(matches!(
+            lobby.push_anime(&game_id, &player("b"), 3, 1, &[11]),
+            MoveResult::Accepted(_)
+        ));
+        assert!(matches!(
+            lobby.push_anime(&game_id, &player("a"), 2, 1, &[10]),
+            MoveResult::Duplicate
+        ));
+        // the seed counts as the first link
+        assert!(matches!(
+            lobby.push_anime(&game_id, &player("a"), 1, 1, &[10]),
+            MoveResult::Duplicate
+        ));
+    }
+
+    #[test]
+    fn guest_cannot_reuse_the_host_id() {
+        let lobby = lobby();
+        let game_id = lobby.reserve(&settings(), GameConfig::default());
+
+        assert!(matches!(
+            lobby.insert(game_id.clone(), "a".to_string(), None, None),
+            LobbyResult::Joined(_)
+        ));
+        assert!(matches!(
+            lobby.insert(game_id, "a".to_string(), None, None),
+            LobbyResult::DuplicateId
+        ));
+    }
+
+    #[test]
+    fn ids_must_be_short_and_plain() {
+        let cases = [
+            ("abc-DEF_123", true),
+            ("", false),
+            (&"a".repeat(MAX_ID_LEN), true),
+            (&"a".repeat(MAX_ID_LEN + 1), false),
+            ("has space", false),
+            ("semi;colon", false),
+            ("ünïcode", false),
+            ("../etc", false),
+        ];
+
+        for (id, valid) in cases {
+            assert_eq!(is_valid_id(id), valid, "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn poisoned_locks_still_hand_out_their_data() {
+        let lock = Arc::new(RwLock::new(vec![1]));
+        let poisoner = lock.clone();
+        let _ = std::thread::spawn(move || {
+            let mut guard = poisoner.write().unwrap();
+            guard.push(2);
+            panic!("poison the lock");
+        })
+        .join();
+
+        assert!(lock.is_poisoned());
+        assert_eq!(*lock.read_or_recover(), [1, 2]);
+        lock.write_or_recover().push(3);
+        assert_eq!(*lock.read_or_recover(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn joiners_past_a_full_room_are_sent_to_spectate() {
+        let lobby = lobby();
+        let game_id = lobby.reserve(&settings(), GameConfig::default());
+        let join = |id: &str| lobby.insert(game_id.clone(), id.to_string(), None, None);
+
+        let LobbyResult::Joined(host) = join("a") else {
+            panic!("host was not seated");
+        };
+        assert_eq!((host.role, host.position), (PlayerRole::Host, 0));
+        assert_eq!(host.host_id, player("a"));
+        let LobbyResult::Joined(guest) = join("b") else {
+            panic!("guest was not seated");
+        };
+        assert_eq!((guest.role, guest.position), (PlayerRole::Guest, 1));
+        assert_eq!(guest.host_id, player("a"));
+        let LobbyResult::Full(room) = join("c") else {
+            panic!("third joiner got a seat");
+        };
+        assert!(room.can_spectate);
+
+        assert!(matches!(
+            lobby.add_spectator(&game_id, None, Sid::new(), 0),
+            SpectateResult::Joined {
+                spectators: 1,
+                resumed: false,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn joiners_are_seated_in_order_while_there_is_room() {
+        let lobby = lobby();
+        let config = GameConfig {
+            max_players: Some(3),
+            ..GameConfig::default()
+        };
+        let game_id = lobby.reserve(&settings(), config);
+
+        let seats = ["a", "b", "c"].map(|id| {
+            let LobbyResult::Joined(reply) =
+                lobby.insert(game_id.clone(), id.to_string(), None, None)
+            else {
+                panic!("{} was not seated", id);
+            };
+            (reply.role, reply.position, reply.host_id)
+        });
+        assert_eq!(
+            seats,
+            [
+                (PlayerRole::Host, 0, player("a")),
+                (PlayerRole::Guest, 1, player("a")),
+                (PlayerRole::Guest, 2, player("a")),
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_spectator_rejoins_as_a_spectator() {
+        let lobby = lobby();
+        let game_id = started(&lobby, "a", "b");
+        let SpectateResult::Joined { token, .. } =
+            lobby.add_spectator(&game_id, None, Sid::new(), 0)
+        else {
+            panic!("spectator was not seated");
+        };
+        // a second socket taking over the seat isn't a second spectator
+        let sid = Sid::new();
+        assert!(matches!(
+            lobby.add_spectator(&game_id, Some(&token), sid, 0),
+            SpectateResult::Joined { spectators: 1, .. }
+        ));
+
+        assert_eq!(lobby.detach_spectator(&game_id, &token, sid), Some(0));
+        assert!(lobby.is_spectator_token(&game_id, &token));
+        assert!(lobby.rejoin(&game_id, &token).is_none());
+        assert!(matches!(
+            lobby.add_spectator(&game_id, Some(&token), Sid::new(), 0),
+            SpectateResult::Joined {
+                spectators: 1,
+                resumed: true,
+                ..
+            }
+        ));
+        assert_eq!(lobby.room_info(&game_id).unwrap().spectators, 1);
+    }
+
+    #[tokio::test]
+    async fn socket_in_a_game_cannot_join_another() {
+        let (addr, app) = serve("http://127.0.0.1:9").await;
+        let first = app.lobby.reserve(&app.config, GameConfig::default());
+        let second = app.lobby.reserve(&app.config, GameConfig::default());
+        let mut client = Client::connect(addr).await;
+
+        let reply = client
+            .call("join_game", json!({ "game_id": first, "player_id": "a" }))
+            .await;
+        assert_eq!(reply["status"], "ok");
+        let reply = client
+            .call("join_game", json!({ "game_id": second, "player_id": "a" }))
+            .await;
+        assert_eq!(reply["error_code"], "already_in_game");
+        assert_eq!(app.lobby.room_info(&second).unwrap().players, []);
+    }
+
+    #[tokio::test]
+    async fn rule_rejections_are_not_move_failures() {
+        // answers `/anime/{id}` with status `id`
+        let jikan = spawn(axum::Router::new().route(
+            "/anime/:id",
+            axum::routing::get(
+                |axum::extract::Path(id): axum::extract::Path<u16>| async move {
+                    StatusCode::from_u16(id).unwrap()
+                },
+            ),
+        ))
+        .await;
+        let (addr, app) = serve(&format!("http://{}", jikan)).await;
+        let (game_id, mut host, _guest) = seat_pair(addr, &app).await;
+        app.lobby.start(&game_id, 1, FirstTurn::Host, 0, 3);
+
+        let reply = host.call("send anime", json!(404)).await;
+        assert_eq!(reply["error_code"], "unknown_anime");
+        assert_eq!(host.event("unknown anime").await, [json!(404)]);
+        let reply = host.call("send anime", json!(500)).await;
+        assert_eq!(reply["error_code"], "upstream_error");
+        // only the move Jikan couldn't judge is reported as failed
+        let failed = host.event("move failed").await;
+        assert_eq!(failed[0]["mal_id"], 500);
+        assert_eq!(failed[0]["reason"], "upstream_error");
+    }
+
+    /// A Jikan whose `/anime/1` turns away the first `limited` requests with
+    /// `429 Too Many Requests` and `Retry-After: retry_after`, along with the
+    /// count of requests it got.
+    async fn rate_limited_jikan(
+        limited: u32,
+        retry_after: &'static str,
+    ) -> (Jikan, Arc<AtomicU32>) {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = hits.clone();
+        let addr = spawn(axum::Router::new().route(
+            "/anime/1",
+            axum::routing::get(move || {
+                let hit = counter.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    if hit < limited {
+                        let retry = [(http::header::RETRY_AFTER, retry_after)];
+                        (StatusCode::TOO_MANY_REQUESTS, retry).into_response()
+                    } else {
+                        axum::Json(json!({ "data": 1 })).into_response()
+                    }
+                }
+            }),
+        ))
+        .await;
+        let jikan = Jikan::new(
+            &format!("http://{}", addr),
+            reqwest::Client::new(),
+            10,
+            Duration::from_secs(60),
+        );
+
+        (jikan, hits)
+    }
+
+    #[tokio::test]
+    async fn jikan_retries_while_rate_limited() {
+        let (jikan, hits) = rate_limited_jikan(2, "0").await;
+
+        let res = jikan.get::<serde_json::Value>("/anime/1").await;
+        assert_eq!(res.unwrap()["data"], 1);
+        assert_eq!(hits.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn jikan_gives_up_after_the_last_retry() {
+        let (jikan, hits) = rate_limited_jikan(u32::MAX, "0").await;
+
+        let res = jikan.get::<serde_json::Value>("/anime/1").await;
+        assert!(matches!(res, Err(JikanError::RateLimited)));
+        assert_eq!(hits.load(Ordering::Relaxed), JIKAN_MAX_RETRIES + 1);
+    }
+
+    #[tokio::test]
+    async fn jikan_waits_as_long_as_retry_after_asks() {
+        let (jikan, hits) = rate_limited_jikan(1, "1").await;
+
+        let started = Instant::now();
+        assert!(jikan.get::<serde_json::Value>("/anime/1").await.is_ok());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(hits.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn slow_jikan_fails_the_start_as_a_timeout() {
+        let jikan = spawn(axum::Router::new().route(
+            "/top/anime",
+            axum::routing::get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                axum::Json(json!({ "data": [] }))
+            }),
+        ))
+        .await;
+        let config = Config {
+            jikan_timeout: Duration::from_millis(100),
+            ..settings()
+        };
+        let (addr, app) = serve_with(config, &format!("http://{}", jikan)).await;
+        let (_, mut host, _guest) = start_pair(addr, &app).await;
+
+        let failed = host.event("start failed").await;
+        assert_eq!(failed[0]["error_code"], "timeout");
+    }
+
+    #[tokio::test]
+    async fn finished_game_is_recycled_once_its_players_are_gone() {
+        let lobby = lobby();
+        let game_id = started(&lobby, "a", "b");
+        lobby.forfeit(&game_id, &player("a"));
+        let join = |id: &str| lobby.insert(game_id.clone(), id.to_string(), None, None);
+
+        assert!(matches!(join("c"), LobbyResult::Full(_)));
+        let (_, io) = SocketIo::new_layer();
+        for id in ["a", "b"] {
+            lobby.schedule_eviction(io.clone(), game_id.clone(), player(id), Duration::MAX);
+        }
+        let LobbyResult::Joined(reply) = join("c") else {
+            panic!("finished game was not recycled");
+        };
+        assert_eq!(reply.role, PlayerRole::Host);
+    }
+
+    #[tokio::test]
+    async fn only_a_game_in_progress_can_be_forfeited() {
+        let lobby = lobby();
+        let game_id = lobby.reserve(&settings(), GameConfig::default());
+        for id in ["a", "b"] {
+            lobby.insert(game_id.clone(), id.to_string(), None, None);
+        }
+
+        assert!(lobby.forfeit(&game_id, &player("a")).is_none());
+        lobby.start(&game_id, 1, FirstTurn::Host, 0, 3);
+        let game = lobby.forfeit(&game_id, &player("a")).unwrap();
+        assert_eq!(game.winner, Some(player("b")));
+        assert!(lobby.forfeit(&game_id, &player("b")).is_none());
+    }
+
+    #[tokio::test]
+    async fn matched_sockets_are_seated_together() {
+        let (addr, app) = serve("http://127.0.0.1:9").await;
+        let mut a = Client::connect(addr).await;
+        let mut b = Client::connect(addr).await;
+
+        assert_eq!(a.call("find_match", json!(null)).await["status"], "ok");
+        let again = a.call("find_match", json!(null)).await;
+        assert_eq!(again["error_code"], "already_queued");
+        assert_eq!(b.call("find_match", json!(null)).await["status"], "ok");
+
+        let found = a.event("match found").await;
+        let game_id = found[0].as_str().unwrap();
+        assert_eq!(found[1]["role"], "host");
+        let found = b.event("match found").await;
+        assert_eq!(found[0], game_id);
+        assert_eq!(found[1]["role"], "guest");
+        assert_eq!(app.lobby.room_info(game_id).unwrap().players.len(), 2);
+
+        let mut c = Client::connect(addr).await;
+        let reply = c.call("join_game", json!({ "game_id": game_id })).await;
+        assert_eq!(reply["error_code"], "room_full");
+    }
+
+    #[tokio::test]
+    async fn join_time_config_is_validated_and_normalized() {
+        let (addr, app) = serve("http://127.0.0.1:9").await;
+        let game_id = app.lobby.reserve(&app.config, GameConfig::default());
+        let mut host = Client::connect(addr).await;
+
+        let join = |config| json!({ "game_id": game_id, "player_id": "a", "config": config });
+        let reply = host
+            .call("join_game", join(json!({ "anime_type": "opera" })))
+            .await;
+        assert_eq!(reply["error_code"], "invalid_config");
+        let reply = host.call("join_game", join(json!({}))).await;
+        assert_eq!(reply["data"]["config"]["anime_type"], "tv");
+        assert_eq!(reply["data"]["config"]["turn_secs"], app.config.turn_secs);
+        assert_eq!(
+            app.lobby.config(&game_id).unwrap().turn_secs,
+            Some(app.config.turn_secs)
+        );
+    }
+
+    #[tokio::test]
+    async fn start_game_seeds_from_the_jikan_listing() {
+        let anime = json!({
+            "mal_id": 21,
+            "title": "One Piece",
+            "title_english": null,
+            "images": { "jpg": { "image_url": null } },
+            "type": "TV",
+        });
+        let jikan = spawn(
+            axum::Router::new()
+                .route(
+                    "/top/anime",
+                    axum::routing::get(move || async move {
+                        axum::Json(
+                            json!({ "data": [anime], "pagination": { "has_next_page": false } }),
+                        )
+                    }),
+                )
+                .route(
+                    "/anime/:id/characters",
+                    axum::routing::get(|| async { axum::Json(json!({ "data": [] })) }),
+                ),
+        )
+        .await;
+        let (addr, app) = serve(&format!("http://{}", jikan)).await;
+        let (_, mut host, mut guest) = start_pair(addr, &app).await;
+
+        for client in [&mut host, &mut guest] {
+            let start = client.event("start game").await;
+            assert_eq!(start[0]["mal_id"], 21);
+            assert_eq!(start[0]["title"], "One Piece");
+            assert_eq!(start[3], "a");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejoin_restores_the_role_behind_the_token() {
+        let (addr, app) = serve("http://127.0.0.1:9").await;
+        let game_id = app.lobby.reserve(&app.config, GameConfig::default());
+        let mut host = Client::connect(addr).await;
+        let joined = host
+            .call("join_game", json!({ "game_id": game_id, "player_id": "a" }))
+            .await;
+        let mut spectator = Client::connect(addr).await;
+        let watching = spectator.call("spectate_game", json!(game_id)).await;
+        let spectators = || app.lobby.room_info(&game_id).unwrap().spectators;
+        assert_eq!(spectators(), 1);
+
+        // a dropped spectator comes back watching, and counted once
+        spectator.close().await;
+        eventually(|| spectators() == 0).await;
+        let mut spectator = Client::connect(addr).await;
+        let token = &watching["data"]["token"];
+        let reply = spectator
+            .call("rejoin_game", json!({ "game_id": game_id, "token": token }))
+            .await;
+        assert_eq!(reply["status"], "ok");
+        let me = spectator.call("whoami", json!(null)).await;
+        assert_eq!(me["data"]["role"], "Spectator");
+        assert_eq!(me["data"]["player_id"], json!(null));
+        assert_eq!(spectators(), 1);
+
+        // a dropped player comes back in their own seat
+        host.close().await;
+        let away = || app.lobby.snapshot()[&game_id].disconnected.len() == 1;
+        eventually(away).await;
+        let mut host = Client::connect(addr).await;
+        let token = &joined["data"]["token"];
+        let reply = host
+            .call("rejoin_game", json!({ "game_id": game_id, "token": token }))
+            .await;
+        assert_eq!(reply["status"], "ok");
+        let me = host.call("whoami", json!(null)).await;
+        assert_eq!(me["data"]["role"], "Player");
+        assert_eq!(me["data"]["player_id"], "a");
+        assert_eq!(
+            app.lobby.room_info(&game_id).unwrap().players,
+            [player("a")]
+        );
+        assert_eq!(spectators(), 1);
+    }
 }